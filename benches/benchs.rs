@@ -28,7 +28,7 @@ fn bench_push(c: &mut Criterion) {
 
             group.bench_function(BenchmarkId::new("AVec<T>", ""), |b| {
                 b.iter(|| {
-                    let vec = AVec::new(cap * thread_count);
+                    let vec = AVec::with_capacity_for(cap, thread_count);
                     thread::scope(|s| {
                         for _ in 0..thread_count {
                             s.spawn(|| {
@@ -49,7 +49,7 @@ fn bench_get(c: &mut Criterion) {
     let el_count = 30000;
 
     let vec: Mutex<Vec<_>> = Mutex::new((0..el_count).collect());
-    let avec = AVec::new(el_count);
+    let avec = AVec::with_capacity(el_count);
     for i in 0..el_count {
         avec.push(i);
     }
@@ -90,6 +90,25 @@ fn bench_get(c: &mut Criterion) {
                 });
             })
         });
+
+        group.bench_function(BenchmarkId::new("AVec<T> unchecked", ""), |b| {
+            b.iter(|| {
+                let sum = AtomicUsize::new(0);
+                thread::scope(|s| {
+                    for _ in 0..thread_count {
+                        s.spawn(|| {
+                            for i in 0..el_count {
+                                // all of them were pushed above and nothing pops
+                                sum.fetch_add(
+                                    unsafe { *avec.get_unchecked(i) },
+                                    std::sync::atomic::Ordering::Relaxed,
+                                );
+                            }
+                        });
+                    }
+                });
+            })
+        });
         group.finish();
     }
 }
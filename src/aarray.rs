@@ -0,0 +1,137 @@
+use std::mem::MaybeUninit;
+use std::ops::Deref;
+
+use crate::lock::{Lock, LockSharedGuard};
+
+struct AArrayInner<T, const N: usize> {
+    data: [MaybeUninit<T>; N],
+    len: usize,
+}
+
+/// fixed-capacity AVec, the elements live inline and the storage never reallocates
+pub struct AArray<T, const N: usize> {
+    lock: Lock<AArrayInner<T, N>>,
+}
+
+impl<T: Send + Sync, const N: usize> AArray<T, N> {
+    pub fn new() -> Self {
+        Self {
+            lock: Lock::new(AArrayInner {
+                data: [const { MaybeUninit::uninit() }; N],
+                len: 0,
+            }),
+        }
+    }
+
+    /// gives the element back if the array is full
+    pub fn push(&self, el: T) -> Result<(), T> {
+        let mut inner = self.lock.lock_exclusive();
+        if inner.len == N {
+            return Err(el);
+        }
+        let len = inner.len;
+        inner.data[len].write(el);
+        inner.len += 1;
+        Ok(())
+    }
+
+    pub fn get(&self, index: usize) -> Option<AArrayRefElement<'_, T, N>> {
+        let inner = self.lock.lock_shared();
+        if index >= inner.len {
+            return None;
+        }
+        Some(AArrayRefElement { inner, index })
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock.lock_shared().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<T: Send + Sync, const N: usize> Default for AArray<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, const N: usize> Drop for AArrayInner<T, N> {
+    fn drop(&mut self) {
+        for el in &mut self.data[..self.len] {
+            unsafe {
+                el.assume_init_drop();
+            }
+        }
+    }
+}
+
+pub struct AArrayRefElement<'a, T, const N: usize> {
+    inner: LockSharedGuard<'a, AArrayInner<T, N>>,
+    index: usize,
+}
+
+impl<'a, T, const N: usize> Deref for AArrayRefElement<'a, T, N> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { self.inner.data[self.index].assume_init_ref() }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::AArray;
+
+    #[test]
+    fn push_until_full() {
+        const THREAD_COUNT: usize = 4;
+        const PER_THREAD: usize = 16;
+        let arr = AArray::<usize, { THREAD_COUNT * PER_THREAD }>::new();
+        assert!(arr.is_empty());
+        thread::scope(|s| {
+            for _ in 0..THREAD_COUNT {
+                s.spawn(|| {
+                    for i in 1..PER_THREAD + 1 {
+                        arr.push(i).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(arr.len(), THREAD_COUNT * PER_THREAD);
+        assert!(!arr.is_empty());
+        assert_eq!(arr.push(42), Err(42));
+        let sum: usize = (0..arr.len()).map(|i| *arr.get(i).unwrap()).sum();
+        assert_eq!(sum, THREAD_COUNT * PER_THREAD * (PER_THREAD + 1) / 2);
+        assert!(arr.get(arr.len()).is_none());
+    }
+
+    #[test]
+    fn drops_elements() {
+        struct Counted<'a>(&'a AtomicUsize);
+        impl Drop for Counted<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let drops: Vec<AtomicUsize> = (0..5).map(|_| AtomicUsize::new(0)).collect();
+        let arr = AArray::<Counted, 4>::new();
+        for d in &drops[..4] {
+            assert!(arr.push(Counted(d)).is_ok());
+        }
+        // full, the caller gets it back and drops it
+        drop(arr.push(Counted(&drops[4])).unwrap_err());
+        assert!(std::ptr::eq(arr.get(1).unwrap().0, &drops[1]));
+        assert!(drops[..4].iter().all(|d| d.load(Ordering::Relaxed) == 0));
+        assert_eq!(drops[4].load(Ordering::Relaxed), 1);
+        drop(arr);
+        assert!(drops.iter().all(|d| d.load(Ordering::Relaxed) == 1));
+    }
+}
@@ -0,0 +1,253 @@
+use std::ops::Deref;
+
+use crate::lock::{Lock, LockSharedGuard};
+use crate::{alloc_buf, dealloc_buf};
+
+struct ADequeInner<T> {
+    data: *mut T,
+    cap: usize,
+    /// physical index of the front element
+    head: usize,
+    len: usize,
+}
+
+unsafe impl<T: Send> Send for ADequeInner<T> {}
+unsafe impl<T: Sync> Sync for ADequeInner<T> {}
+
+impl<T> ADequeInner<T> {
+    /// physical slot of the logical index `i`, which may be one past the end
+    fn slot(&self, i: usize) -> *mut T {
+        unsafe { self.data.add((self.head + i) % self.cap) }
+    }
+
+    fn grow_if_full(&mut self) {
+        if self.len < self.cap {
+            return;
+        }
+        let new_cap = (self.cap * 2).max(1);
+        let data = alloc_buf::<T>(new_cap);
+        unsafe {
+            // straighten the ring out while copying, the front lands at 0
+            for i in 0..self.len {
+                std::ptr::copy_nonoverlapping(self.slot(i), data.add(i), 1);
+            }
+            dealloc_buf(self.data, self.cap);
+        }
+        self.data = data;
+        self.cap = new_cap;
+        self.head = 0;
+    }
+}
+
+/// double-ended AVec on a ring buffer.
+/// every push and pop takes the exclusive lock, only reads run under the shared one
+pub struct ADeque<T> {
+    lock: Lock<ADequeInner<T>>,
+}
+
+impl<T: Send + Sync> ADeque<T> {
+    pub fn new() -> Self {
+        Self::with_capacity(0)
+    }
+
+    pub fn with_capacity(cap: usize) -> Self {
+        Self {
+            lock: Lock::new(ADequeInner {
+                data: alloc_buf(cap),
+                cap,
+                head: 0,
+                len: 0,
+            }),
+        }
+    }
+
+    pub fn push_back(&self, el: T) {
+        let mut inner = self.lock.lock_exclusive();
+        inner.grow_if_full();
+        unsafe { inner.slot(inner.len).write(el) };
+        inner.len += 1;
+    }
+
+    pub fn push_front(&self, el: T) {
+        let mut inner = self.lock.lock_exclusive();
+        inner.grow_if_full();
+        inner.head = (inner.head + inner.cap - 1) % inner.cap;
+        unsafe { inner.data.add(inner.head).write(el) };
+        inner.len += 1;
+    }
+
+    pub fn pop_back(&self) -> Option<T> {
+        let mut inner = self.lock.lock_exclusive();
+        if inner.len == 0 {
+            return None;
+        }
+        inner.len -= 1;
+        Some(unsafe { inner.slot(inner.len).read() })
+    }
+
+    pub fn pop_front(&self) -> Option<T> {
+        let mut inner = self.lock.lock_exclusive();
+        if inner.len == 0 {
+            return None;
+        }
+        let el = unsafe { inner.data.add(inner.head).read() };
+        inner.head = (inner.head + 1) % inner.cap;
+        inner.len -= 1;
+        Some(el)
+    }
+
+    /// `0` is the front
+    pub fn get(&self, index: usize) -> Option<ADequeRefElement<'_, T>> {
+        let inner = self.lock.lock_shared();
+        if index >= inner.len {
+            return None;
+        }
+        Some(ADequeRefElement { inner, index })
+    }
+
+    pub fn len(&self) -> usize {
+        self.lock.lock_shared().len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.lock.lock_shared().cap
+    }
+}
+
+impl<T: Send + Sync> Default for ADeque<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T> Drop for ADequeInner<T> {
+    fn drop(&mut self) {
+        unsafe {
+            for i in 0..self.len {
+                std::ptr::drop_in_place(self.slot(i));
+            }
+            dealloc_buf(self.data, self.cap);
+        }
+    }
+}
+
+pub struct ADequeRefElement<'a, T> {
+    inner: LockSharedGuard<'a, ADequeInner<T>>,
+    index: usize,
+}
+
+impl<'a, T> Deref for ADequeRefElement<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.inner.slot(self.index) }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::ADeque;
+
+    #[test]
+    fn wraps_around() {
+        let deque = ADeque::with_capacity(4);
+        assert!(deque.is_empty());
+        deque.push_back(1);
+        deque.push_back(2);
+        deque.push_front(0);
+        deque.push_front(-1);
+        // full with the head wrapped to the end, this one has to straighten it out
+        deque.push_back(3);
+        let seen: Vec<_> = (0..deque.len()).map(|i| *deque.get(i).unwrap()).collect();
+        assert_eq!(seen, [-1, 0, 1, 2, 3]);
+        assert_eq!(deque.pop_front(), Some(-1));
+        assert_eq!(deque.pop_back(), Some(3));
+        assert_eq!(deque.len(), 3);
+        assert!(!deque.is_empty());
+        assert!(deque.get(3).is_none());
+    }
+
+    #[test]
+    fn mixed_ends() {
+        const THREAD_COUNT: usize = 4;
+        const PER_THREAD: usize = 1000;
+        let deque = ADeque::new();
+        thread::scope(|s| {
+            for t in 0..THREAD_COUNT {
+                let deque = &deque;
+                s.spawn(move || {
+                    for i in 0..PER_THREAD {
+                        // evens go to the front, odds to the back
+                        let el = t * PER_THREAD + i;
+                        if el.is_multiple_of(2) {
+                            deque.push_front(el);
+                        } else {
+                            deque.push_back(el);
+                        }
+                        if i % 4 == 3 {
+                            let popped = if t % 2 == 0 {
+                                deque.pop_front()
+                            } else {
+                                deque.pop_back()
+                            };
+                            assert!(popped.is_some());
+                        }
+                    }
+                });
+            }
+        });
+        let total = THREAD_COUNT * PER_THREAD;
+        assert_eq!(deque.len(), total - total / 4);
+        let mut rest = Vec::new();
+        while let Some(el) = deque.pop_front() {
+            rest.push(el);
+        }
+        // whatever is left keeps the evens in front of the odds
+        let first_odd = rest.iter().position(|el| el % 2 == 1).unwrap_or(rest.len());
+        assert!(rest[..first_odd].iter().all(|el| el.is_multiple_of(2)));
+        assert!(rest[first_odd..].iter().all(|el| el % 2 == 1));
+        rest.sort();
+        rest.dedup();
+        assert_eq!(rest.len(), total - total / 4);
+    }
+
+    #[test]
+    fn drops_elements() {
+        struct Counted<'a>(&'a AtomicUsize);
+        impl Drop for Counted<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let drops: Vec<AtomicUsize> = (0..12).map(|_| AtomicUsize::new(0)).collect();
+        let count = |i: usize| drops[i].load(Ordering::Relaxed);
+        // small enough that it wraps and grows along the way
+        let deque = ADeque::with_capacity(2);
+        for (i, d) in drops.iter().enumerate() {
+            if i % 2 == 0 {
+                deque.push_back(Counted(d));
+            } else {
+                deque.push_front(Counted(d));
+            }
+            if i % 3 == 2 {
+                drop(deque.pop_front());
+                drop(deque.pop_back());
+            }
+        }
+        // 12 pushed, 8 popped and already dropped, once each
+        assert_eq!(deque.len(), 4);
+        assert_eq!((0..12).filter(|&i| count(i) == 1).count(), 8);
+        assert!((0..12).all(|i| count(i) <= 1));
+        drop(deque);
+        assert!((0..12).all(|i| count(i) == 1));
+    }
+}
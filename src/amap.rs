@@ -0,0 +1,115 @@
+use std::collections::HashMap;
+use std::hash::{BuildHasher, Hash, RandomState};
+
+use crate::lock::Lock;
+
+const DEFAULT_SHARDS: usize = 16;
+
+/// hash map split into `Lock`ed shards, so threads working on different keys
+/// mostly don't touch the same lock
+pub struct AMap<K, V> {
+    shards: Box<[Lock<HashMap<K, V>>]>,
+    hasher: RandomState,
+}
+
+impl<K: Hash + Eq, V> AMap<K, V> {
+    pub fn new() -> Self {
+        Self::with_shards(DEFAULT_SHARDS)
+    }
+
+    /// more shards means less contention but a slower `len`
+    pub fn with_shards(n: usize) -> Self {
+        assert!(n > 0, "AMap needs at least one shard");
+        Self {
+            shards: (0..n).map(|_| Lock::new(HashMap::new())).collect(),
+            hasher: RandomState::new(),
+        }
+    }
+
+    fn shard(&self, key: &K) -> &Lock<HashMap<K, V>> {
+        let hash = self.hasher.hash_one(key) as usize;
+        &self.shards[hash % self.shards.len()]
+    }
+
+    pub fn insert(&self, key: K, value: V) -> Option<V> {
+        self.shard(&key).lock_exclusive().insert(key, value)
+    }
+
+    pub fn remove(&self, key: &K) -> Option<V> {
+        self.shard(key).lock_exclusive().remove(key)
+    }
+
+    /// clones the value out, the shard is only locked for the lookup
+    pub fn get(&self, key: &K) -> Option<V>
+    where
+        V: Clone,
+    {
+        self.shard(key).lock_shared().get(key).cloned()
+    }
+
+    /// `f` runs on the value under the shard's exclusive lock
+    pub fn update<R>(&self, key: &K, f: impl FnOnce(&mut V) -> R) -> Option<R> {
+        self.shard(key).lock_exclusive().get_mut(key).map(f)
+    }
+
+    /// goes over the shards one by one, so it's not a snapshot if others are inserting
+    pub fn len(&self) -> usize {
+        self.shards.iter().map(|s| s.lock_shared().len()).sum()
+    }
+
+    /// stops at the first shard with anything in it, same caveat as `len`
+    pub fn is_empty(&self) -> bool {
+        self.shards.iter().all(|s| s.lock_shared().is_empty())
+    }
+}
+
+impl<K: Hash + Eq, V> Default for AMap<K, V> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::AMap;
+
+    #[test]
+    fn no_lost_updates() {
+        const THREAD_COUNT: usize = 4;
+        const PER_THREAD: usize = 1000;
+        const COUNTERS: usize = 10;
+        let map = AMap::with_shards(8);
+        assert!(map.is_empty());
+        // counter keys sit above everything the threads insert
+        let counter = |i: usize| THREAD_COUNT * PER_THREAD + i % COUNTERS;
+        for i in 0..COUNTERS {
+            map.insert(counter(i), 0);
+        }
+        thread::scope(|s| {
+            for t in 0..THREAD_COUNT {
+                let map = &map;
+                let counter = &counter;
+                s.spawn(move || {
+                    for i in 0..PER_THREAD {
+                        let key = t * PER_THREAD + i;
+                        assert!(map.insert(key, key * 2).is_none());
+                        assert_eq!(map.get(&key), Some(key * 2));
+                        // everyone bumps the same few counters too
+                        map.update(&counter(i), |v| *v += 1).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(map.len(), THREAD_COUNT * PER_THREAD + COUNTERS);
+        assert!(!map.is_empty());
+        for key in 0..THREAD_COUNT * PER_THREAD {
+            assert_eq!(map.get(&key), Some(key * 2));
+        }
+        let bumps: usize = (0..COUNTERS).map(|i| map.get(&counter(i)).unwrap()).sum();
+        assert_eq!(bumps, THREAD_COUNT * PER_THREAD);
+        assert_eq!(map.remove(&42), Some(84));
+        assert_eq!(map.get(&42), None);
+    }
+}
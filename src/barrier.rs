@@ -0,0 +1,97 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::sync::backoff;
+
+const COUNT_MASK: u64 = u32::MAX as u64;
+const GENERATION_ONE: u64 = 1 << 32;
+
+/// lets `n` threads wait for each other, can be reused right after every round
+pub struct Barrier {
+    n: u32,
+    /// generation in the high half, threads arrived in this generation in the low half.
+    /// one word so arriving and starting the next generation can't interleave
+    state: AtomicU64,
+}
+
+impl Barrier {
+    /// `0` behaves like `1`, nobody waits
+    pub const fn new(n: u32) -> Self {
+        Self {
+            n: if n == 0 { 1 } else { n },
+            state: AtomicU64::new(0),
+        }
+    }
+
+    /// spins until `n` threads have called `wait` in this generation, backing off
+    /// to yielding if it takes a while. returns `true` for the thread that came last
+    pub fn wait(&self) -> bool {
+        let mut current = self.state.load(Ordering::Relaxed);
+        let generation = loop {
+            let generation = current & !COUNT_MASK;
+            let new = if (current & COUNT_MASK) + 1 == self.n as u64 {
+                generation.wrapping_add(GENERATION_ONE)
+            } else {
+                current + 1
+            };
+            // acquire and release so everything done before `wait` on every thread
+            // is visible after it on every other one
+            match self.state.compare_exchange_weak(
+                current,
+                new,
+                Ordering::AcqRel,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) if new & COUNT_MASK == 0 => return true,
+                Ok(_) => break generation,
+                Err(actual) => current = actual,
+            }
+        };
+        let spin_limit = crate::lock::spin_limit();
+        let mut spins = 0;
+        while self.state.load(Ordering::Acquire) & !COUNT_MASK == generation {
+            backoff(&mut spins, spin_limit);
+        }
+        false
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::Barrier;
+
+    #[test]
+    fn two_generations() {
+        const THREAD_COUNT: usize = 5;
+        let barrier = Barrier::new(THREAD_COUNT as u32);
+        let arrived = AtomicUsize::new(0);
+        let leaders = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..THREAD_COUNT {
+                s.spawn(|| {
+                    for round in 1..=2 {
+                        arrived.fetch_add(1, Ordering::Relaxed);
+                        if barrier.wait() {
+                            leaders.fetch_add(1, Ordering::Relaxed);
+                        }
+                        // nobody gets past before everyone is in, and nobody from the
+                        // next round can be counted yet since they all wait again below
+                        assert_eq!(arrived.load(Ordering::Relaxed), THREAD_COUNT * round);
+                        barrier.wait();
+                    }
+                });
+            }
+        });
+        assert_eq!(leaders.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn single_doesnt_wait() {
+        assert!(Barrier::new(1).wait());
+        assert!(Barrier::new(0).wait());
+    }
+}
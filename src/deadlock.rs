@@ -0,0 +1,62 @@
+//! lock ordering registry behind the `deadlock-detection` feature.
+//! every thread keeps a stack of the locks it holds, taking lock `b` while holding `a`
+//! records `a -> b`, and a later `b -> a` from anywhere panics as a potential deadlock
+
+use std::cell::RefCell;
+use std::collections::HashSet;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{LazyLock, Mutex};
+
+static NEXT_ID: AtomicUsize = AtomicUsize::new(1);
+static ORDERS: LazyLock<Mutex<HashSet<(usize, usize)>>> = LazyLock::new(Default::default);
+
+thread_local! {
+    static HELD: RefCell<Vec<usize>> = const { RefCell::new(Vec::new()) };
+}
+
+pub(crate) fn next_id() -> usize {
+    NEXT_ID.fetch_add(1, Ordering::Relaxed)
+}
+
+/// call before blocking on the lock, so the panic doesn't leave it locked
+pub(crate) fn acquire(id: usize) {
+    HELD.with(|held| {
+        let mut held = held.borrow_mut();
+        let conflict = {
+            let mut orders = ORDERS.lock().unwrap_or_else(|e| e.into_inner());
+            let conflict = held
+                .iter()
+                .copied()
+                .find(|&h| h != id && orders.contains(&(id, h)));
+            if conflict.is_none() {
+                for &h in held.iter().filter(|&&h| h != id) {
+                    orders.insert((h, id));
+                }
+            }
+            conflict
+        };
+        if let Some(h) = conflict {
+            panic!(
+                "potential deadlock: lock #{} taken while holding lock #{}, \
+                 but they were taken in the opposite order before",
+                id, h
+            );
+        }
+        held.push(id);
+    })
+}
+
+/// for try-locks, they never block so there's no order to check
+pub(crate) fn acquired(id: usize) {
+    HELD.with(|held| held.borrow_mut().push(id));
+}
+
+pub(crate) fn release(id: usize) {
+    // guards can be moved to other threads, in that case there's nothing to pop here
+    let _ = HELD.try_with(|held| {
+        let mut held = held.borrow_mut();
+        if let Some(pos) = held.iter().rposition(|&h| h == id) {
+            held.remove(pos);
+        }
+    });
+}
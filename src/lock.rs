@@ -1,12 +1,89 @@
 use std::cell::UnsafeCell;
-use std::hint;
 use std::mem::forget;
 use std::ops::{Deref, DerefMut};
-use std::sync::atomic::*;
+use std::sync::{LockResult, PoisonError};
+use std::time::{Duration, Instant};
+
+use crate::sync::atomic::*;
+use crate::sync::backoff;
+
+#[cfg(all(feature = "park", not(loom)))]
+mod park;
+pub mod raw;
+#[cfg(all(feature = "lock_api", not(loom)))]
+pub mod rwlock;
+
+/// how many times the acquire loops spin on a held lock before they start yielding
+static SPIN_LIMIT: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(64);
+
+/// tunes the spin-then-yield point of every acquire loop in the crate, for the whole process.
+/// `0` yields right away, higher values burn more cpu hoping the holder is about to finish
+pub fn set_spin_limit(n: u32) {
+    SPIN_LIMIT.store(n, std::sync::atomic::Ordering::Relaxed);
+}
+
+/// read once per acquire attempt, a change applies to the next one
+pub(crate) fn spin_limit() -> u32 {
+    #[cfg(test)]
+    if let Some(n) = TEST_SPIN_LIMIT.with(|l| l.get()) {
+        return n;
+    }
+    SPIN_LIMIT.load(std::sync::atomic::Ordering::Relaxed)
+}
+
+#[cfg(test)]
+thread_local! {
+    /// takes over from `SPIN_LIMIT` on this thread only, so a test can't change it under the others
+    static TEST_SPIN_LIMIT: std::cell::Cell<Option<u32>> = const { std::cell::Cell::new(None) };
+}
+
+/// runs `f` with `spin_limit()` returning `n` on the calling thread, the threads `f`
+/// spawns need their own call
+#[cfg(all(test, not(loom)))]
+pub(crate) fn with_test_spin_limit<R>(n: u32, f: impl FnOnce() -> R) -> R {
+    struct Restore(Option<u32>);
+    impl Drop for Restore {
+        fn drop(&mut self) {
+            TEST_SPIN_LIMIT.with(|l| l.set(self.0));
+        }
+    }
+    let _restore = Restore(TEST_SPIN_LIMIT.with(|l| l.replace(Some(n))));
+    f()
+}
 
 pub struct Lock<T> {
     val: AtomicU64,
+    /// ticket the next writer takes
+    next_ticket: AtomicU32,
+    /// ticket of the writer whose turn it is, it has the lock or is waiting for readers to leave
+    serving: AtomicU32,
     data: UnsafeCell<T>,
+    /// set when an exclusive guard is dropped by a panic, only the `_checked` locks look at it
+    poisoned: std::sync::atomic::AtomicBool,
+    /// backoff rounds the slow paths went through, see `spin_stats`
+    #[cfg(feature = "metrics")]
+    shared_spins: std::sync::atomic::AtomicU64,
+    #[cfg(feature = "metrics")]
+    exclusive_spins: std::sync::atomic::AtomicU64,
+    /// `0` until the lock is first used if it was made by `const_new`
+    #[cfg(feature = "deadlock-detection")]
+    id: std::sync::atomic::AtomicUsize,
+    /// threads asleep in an acquire, and how many, so a release can skip the queue when it's empty
+    #[cfg(all(feature = "park", not(loom)))]
+    parked: crate::spinmutex::SpinMutex<Vec<std::thread::Thread>>,
+    #[cfg(all(feature = "park", not(loom)))]
+    parked_count: std::sync::atomic::AtomicUsize,
+}
+
+/// how long the acquires of one `Lock` had to wait, in backoff rounds. a lock
+/// nobody fights over stays at zero, the uncontended path doesn't count anything
+#[cfg(feature = "metrics")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct SpinStats {
+    /// rounds spent by `lock_shared` waiting on writers or the reader limit
+    pub shared: u64,
+    /// rounds spent by `lock_exclusive` waiting for its turn and for readers to leave
+    pub exclusive: u64,
 }
 
 pub struct LockSharedGuard<'a, T> {
@@ -14,8 +91,9 @@ pub struct LockSharedGuard<'a, T> {
 }
 
 impl<'a, T> Drop for LockSharedGuard<'a, T> {
+    #[inline]
     fn drop(&mut self) {
-        self.inner.val.fetch_sub(1, Ordering::Acquire);
+        unsafe { self.inner.raw_unlock_shared() }
     }
 }
 
@@ -27,31 +105,98 @@ impl<'a, T> Deref for LockSharedGuard<'a, T> {
     }
 }
 
+/// another shared guard on the same lock, no waiting since we're already holding it
+impl<'a, T> Clone for LockSharedGuard<'a, T> {
+    fn clone(&self) -> Self {
+        #[cfg(feature = "deadlock-detection")]
+        crate::deadlock::acquire(self.inner.deadlock_id());
+        self.inner.add_share();
+        Self::new(self.inner)
+    }
+}
+
 impl<'a, T> LockSharedGuard<'a, T> {
     fn new(inner: &'a Lock<T>) -> Self {
         Self { inner }
     }
 
+    /// readers holding the lock right now, us included. `1` means an `upgrade`
+    /// only waits for other writers, it can be stale by the time it's looked at
+    pub fn reader_count_hint(&self) -> u64 {
+        // can't be exclusive while we're holding a share, a writer can be pending though
+        self.inner.val.load(Ordering::Relaxed) & Lock::<T>::READERS_MASK
+    }
+
     /// there's a gap here, make sure to double check
-    /// the condition you entered it with in the first place
+    /// the condition you entered it with in the first place.
+    /// the shared lock is fully given up before waiting for the exclusive one,
+    /// so if that panics nothing is left locked
     pub fn upgrade(self) -> LockExclusiveGuard<'a, T> {
         let lock = self.inner;
         drop(self);
         lock.lock_exclusive()
     }
+
+    /// `upgrade` without the gap, only works if we're the one reader and no writer is
+    /// queued. otherwise the shared guard comes back untouched
+    pub fn try_upgrade(self) -> Result<LockExclusiveGuard<'a, T>, Self> {
+        let lock = self.inner;
+        let serving = lock.serving.load(Ordering::Relaxed);
+        // a ticket that's served right away, same as `try_lock_exclusive`
+        if lock
+            .next_ticket
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return Err(self);
+        }
+        if lock
+            .val
+            .compare_exchange(
+                1,
+                Lock::<T>::LOCK_EXCL,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            lock.serving.fetch_add(1, Ordering::Release);
+            lock.wake_waiters();
+            return Err(self);
+        }
+        // our share turned into the exclusive lock, the exclusive guard gives it back
+        forget(self);
+        Ok(LockExclusiveGuard::new(lock))
+    }
+
+    /// keeps the shared lock but only shows the part of `T` that `f` picks
+    pub fn map<U: ?Sized, F: FnOnce(&T) -> &U>(self, f: F) -> MappedSharedGuard<'a, U> {
+        let lock = self.inner;
+        // `self` is still around while `f` runs, so a panic in there unlocks
+        let data = f(unsafe { &*lock.data.get() });
+        forget(self);
+        MappedSharedGuard { lock, data }
+    }
 }
 
 // -------------------------------------------------
 
 pub struct LockExclusiveGuard<'a, T> {
     inner: &'a Lock<T>,
+    /// already unwinding when the lock was taken, that panic didn't happen under it
+    panicking: bool,
 }
 
 impl<'a, T> Drop for LockExclusiveGuard<'a, T> {
+    #[inline]
     fn drop(&mut self) {
-        self.inner
-            .val
-            .store(Lock::<T>::LOCK_FREE, Ordering::Release);
+        self.inner.poison_if_panicked(self.panicking);
+        unsafe { self.inner.raw_unlock_exclusive() }
     }
 }
 
@@ -70,79 +215,403 @@ impl<'a, T> DerefMut for LockExclusiveGuard<'a, T> {
 }
 
 impl<'a, T> LockExclusiveGuard<'a, T> {
+    fn new(inner: &'a Lock<T>) -> Self {
+        Self {
+            inner,
+            panicking: std::thread::panicking(),
+        }
+    }
+
     /// the lock stays locked without gaps
     pub fn downgrade(self) -> LockSharedGuard<'a, T> {
+        // nobody else can touch `val` while it's exclusive, so a plain store is enough.
+        // release pairs with the acquire of the readers joining us
         self.inner.val.store(1, Ordering::Release);
+        // we're not a writer anymore, the next one can queue up behind us
+        self.inner.serving.fetch_add(1, Ordering::Release);
+        self.inner.wake_waiters();
         let inner = self.inner;
         forget(self);
         LockSharedGuard::new(inner)
     }
+
+    /// keeps the exclusive lock but only hands out the part of `T` that `f` picks
+    pub fn map<U: ?Sized, F: FnOnce(&mut T) -> &mut U>(self, f: F) -> MappedExclusiveGuard<'a, U> {
+        let (lock, panicking) = (self.inner, self.panicking);
+        let data = f(unsafe { &mut *lock.data.get() });
+        forget(self);
+        MappedExclusiveGuard {
+            lock,
+            data,
+            panicking,
+        }
+    }
+}
+
+// -------------------------------------------------
+
+/// lets the mapped guards unlock without knowing the `T` they came from
+trait RawUnlock {
+    unsafe fn unlock_shared(&self);
+    unsafe fn unlock_exclusive(&self, panicking: bool);
+}
+
+impl<T> RawUnlock for Lock<T> {
+    unsafe fn unlock_shared(&self) {
+        self.raw_unlock_shared()
+    }
+
+    unsafe fn unlock_exclusive(&self, panicking: bool) {
+        self.poison_if_panicked(panicking);
+        self.raw_unlock_exclusive()
+    }
+}
+
+pub struct MappedSharedGuard<'a, U: ?Sized> {
+    lock: &'a dyn RawUnlock,
+    data: &'a U,
+}
+
+impl<U: ?Sized> Drop for MappedSharedGuard<'_, U> {
+    fn drop(&mut self) {
+        unsafe { self.lock.unlock_shared() }
+    }
+}
+
+impl<U: ?Sized> Deref for MappedSharedGuard<'_, U> {
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        self.data
+    }
+}
+
+// the lock is only touched on drop, sharing the guard just shares `&U`
+unsafe impl<U: ?Sized + Sync> Sync for MappedSharedGuard<'_, U> {}
+
+pub struct MappedExclusiveGuard<'a, U: ?Sized> {
+    lock: &'a dyn RawUnlock,
+    data: &'a mut U,
+    panicking: bool,
 }
 
+impl<U: ?Sized> Drop for MappedExclusiveGuard<'_, U> {
+    fn drop(&mut self) {
+        unsafe { self.lock.unlock_exclusive(self.panicking) }
+    }
+}
+
+impl<U: ?Sized> Deref for MappedExclusiveGuard<'_, U> {
+    type Target = U;
+
+    fn deref(&self) -> &U {
+        self.data
+    }
+}
+
+impl<U: ?Sized> DerefMut for MappedExclusiveGuard<'_, U> {
+    fn deref_mut(&mut self) -> &mut U {
+        self.data
+    }
+}
+
+unsafe impl<U: ?Sized + Sync> Sync for MappedExclusiveGuard<'_, U> {}
+
 // -------------------------------------------------
 
 impl<T> Lock<T> {
+    // `val` is laid out as
+    //   bit 63      exclusive, nothing else is set with it
+    //   bit 62      a writer whose turn it is waits for the readers to leave
+    //   bits 0..62  readers holding the lock
     const LOCK_FREE: u64 = 0;
     const LOCK_EXCL: u64 = 0x1 << 63;
+    const LOCK_PENDING: u64 = 0x1 << 62;
+    const READERS_MASK: u64 = Self::LOCK_PENDING - 1;
+    /// shared locks past this wait for a reader to leave, the count never reaches the other bits
+    pub const MAX_READERS: u64 = Self::READERS_MASK;
 
+    /// recursive: a thread that already holds shared guards can take more,
+    /// even while a writer is waiting. new readers hold back for a bit when a
+    /// writer is pending, but they don't wait on it forever since they might be
+    /// what it's waiting for. every guard gives back its own share
+    #[inline]
     pub fn lock_shared(&self) -> LockSharedGuard<'_, T> {
-        let mut current = Self::LOCK_FREE;
-        loop {
-            match self.val.compare_exchange_weak(
-                current,
-                current + 1,
-                Ordering::Acquire,
-                Ordering::Relaxed,
-            ) {
-                Ok(_) => break,
-                Err(Self::LOCK_EXCL) => {
-                    current = 0;
-                    hint::spin_loop();
-                }
-                Err(actual) => {
-                    current = actual;
-                    hint::spin_loop();
-                }
-            }
-        }
+        self.raw_lock_shared();
         LockSharedGuard::new(self)
     }
 
+    #[inline]
     pub fn lock_exclusive(&self) -> LockExclusiveGuard<'_, T> {
+        self.raw_lock_exclusive();
+        LockExclusiveGuard::new(self)
+    }
+
+    /// `lock_shared`, but `Err` if a writer panicked while holding the lock,
+    /// like `std::sync::RwLock::read`. the guard is in the error either way
+    pub fn lock_shared_checked(&self) -> LockResult<LockSharedGuard<'_, T>> {
+        let guard = self.lock_shared();
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// `lock_exclusive`, but `Err` if a writer panicked while holding the lock
+    pub fn lock_exclusive_checked(&self) -> LockResult<LockExclusiveGuard<'_, T>> {
+        let guard = self.lock_exclusive();
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    /// whether an exclusive guard was dropped by a panic, the data may be halfway through a change
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(std::sync::atomic::Ordering::Relaxed)
+    }
+
+    /// for when the data has been checked or fixed up after a panic
+    pub fn clear_poison(&self) {
+        self.poisoned
+            .store(false, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// totals since the lock was made, the try and timeout variants don't spin so they aren't in here
+    #[cfg(feature = "metrics")]
+    pub fn spin_stats(&self) -> SpinStats {
+        use std::sync::atomic::Ordering::Relaxed;
+        SpinStats {
+            shared: self.shared_spins.load(Relaxed),
+            exclusive: self.exclusive_spins.load(Relaxed),
+        }
+    }
+
+    /// the unlock that follows releases the store, the next holder sees it
+    fn poison_if_panicked(&self, was_panicking: bool) {
+        if !was_panicking && std::thread::panicking() {
+            self.poisoned
+                .store(true, std::sync::atomic::Ordering::Relaxed);
+        }
+    }
+
+    /// hands a shared guard to `f`, which can `upgrade` it but can't let it
+    /// (or what it turns into) outlive the call
+    pub fn scope_shared<R>(&self, f: impl for<'g> FnOnce(LockSharedGuard<'g, T>) -> R) -> R {
+        f(self.lock_shared())
+    }
+
+    /// `None` right away if a writer has it, never spins waiting for one
+    pub fn try_lock_shared(&self) -> Option<LockSharedGuard<'_, T>> {
+        self.raw_try_lock_shared()
+            .then(|| LockSharedGuard::new(self))
+    }
+
+    /// `None` right away if anyone holds it or another writer is queued
+    pub fn try_lock_exclusive(&self) -> Option<LockExclusiveGuard<'_, T>> {
+        // not `then_some`, building the guard eagerly would unlock on drop
+        self.raw_try_lock_exclusive()
+            .then(|| LockExclusiveGuard::new(self))
+    }
+
+    /// `None` if a writer still has it after `dur`
+    pub fn lock_shared_timeout(&self, dur: Duration) -> Option<LockSharedGuard<'_, T>> {
+        self.poll_until(dur, Self::raw_try_lock_shared)
+            .then(|| LockSharedGuard::new(self))
+    }
+
+    /// `None` if it's still taken after `dur`. it doesn't line up behind other writers
+    /// like `lock_exclusive`, it keeps trying to slip in whenever the lock is free
+    pub fn lock_exclusive_timeout(&self, dur: Duration) -> Option<LockExclusiveGuard<'_, T>> {
+        self.poll_until(dur, Self::raw_try_lock_exclusive)
+            .then(|| LockExclusiveGuard::new(self))
+    }
+
+    fn poll_until(&self, dur: Duration, try_lock: impl Fn(&Self) -> bool) -> bool {
+        // too far out to represent is as good as never
+        let deadline = Instant::now().checked_add(dur);
+        let spin_limit = spin_limit();
+        let mut spins = 0;
         loop {
-            match self.val.compare_exchange_weak(
-                Self::LOCK_FREE,
-                Self::LOCK_EXCL,
-                Ordering::Acquire,
-                Ordering::Relaxed,
-            ) {
-                Ok(_) => break,
-                Err(_) => {
-                    hint::spin_loop();
-                }
+            if try_lock(self) {
+                return true;
+            }
+            // reading the clock costs more than a try, only do it every few rounds
+            if spins % 8 == 0 && deadline.is_some_and(|d| Instant::now() >= d) {
+                return false;
+            }
+            backoff(&mut spins, spin_limit);
+        }
+    }
+
+    /// runs `f` only if the shared lock can be had without waiting
+    pub fn try_with_shared<R>(&self, f: impl FnOnce(&T) -> R) -> Option<R> {
+        if !self.raw_try_lock_shared() {
+            return None;
+        }
+        Some(f(&LockSharedGuard::new(self)))
+    }
+
+    /// runs `f` only if the exclusive lock can be had without waiting
+    pub fn try_with_exclusive<R>(&self, f: impl FnOnce(&mut T) -> R) -> Option<R> {
+        if !self.raw_try_lock_exclusive() {
+            return None;
+        }
+        Some(f(&mut LockExclusiveGuard::new(self)))
+    }
+
+    /// mutates the value in place under the exclusive lock, which is released right after `f`
+    pub fn modify(&self, f: impl FnOnce(&mut T)) {
+        f(&mut self.lock_exclusive());
+    }
+
+    /// like the atomics' `fetch_update`: `Ok(previous)` if `f` gave a new value,
+    /// `Err(previous)` if it didn't. `f` runs once since nobody can race us here
+    pub fn fetch_update<F: FnMut(T) -> Option<T>>(&self, mut f: F) -> Result<T, T>
+    where
+        T: Copy,
+    {
+        let mut guard = self.lock_exclusive();
+        let prev = *guard;
+        match f(prev) {
+            Some(next) => {
+                *guard = next;
+                Ok(prev)
             }
+            None => Err(prev),
+        }
+    }
+
+    /// runs `body` on a part of the data under the exclusive lock
+    pub fn scoped_map<U, R>(
+        &self,
+        project: impl FnOnce(&mut T) -> &mut U,
+        body: impl FnOnce(&mut U) -> R,
+    ) -> R {
+        let mut guard = self.lock_exclusive();
+        body(project(&mut guard))
+    }
+
+    /// no locking needed, `&mut` already rules out everyone else
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// the order to take several locks in. the address can change when the owner
+    /// moves, which would confuse the deadlock detector, so it uses the lock id instead
+    pub(crate) fn order_key(&self) -> usize {
+        #[cfg(feature = "deadlock-detection")]
+        return self.deadlock_id();
+        #[cfg(not(feature = "deadlock-detection"))]
+        return self as *const Self as usize;
+    }
+
+    #[cfg(feature = "deadlock-detection")]
+    pub(crate) fn deadlock_id(&self) -> usize {
+        use std::sync::atomic::Ordering::Relaxed;
+        let id = self.id.load(Relaxed);
+        if id != 0 {
+            return id;
+        }
+        // whoever loses the race takes the winner's
+        match self
+            .id
+            .compare_exchange(0, crate::deadlock::next_id(), Relaxed, Relaxed)
+        {
+            Ok(_) => self.id.load(Relaxed),
+            Err(id) => id,
         }
-        LockExclusiveGuard { inner: self }
     }
 
     pub fn new(data: T) -> Self {
         Self {
             val: AtomicU64::new(Self::LOCK_FREE),
+            next_ticket: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
+            data: UnsafeCell::new(data),
+            poisoned: std::sync::atomic::AtomicBool::new(false),
+            #[cfg(feature = "metrics")]
+            shared_spins: std::sync::atomic::AtomicU64::new(0),
+            #[cfg(feature = "metrics")]
+            exclusive_spins: std::sync::atomic::AtomicU64::new(0),
+            #[cfg(all(feature = "park", not(loom)))]
+            parked: crate::spinmutex::SpinMutex::new(Vec::new()),
+            #[cfg(all(feature = "park", not(loom)))]
+            parked_count: std::sync::atomic::AtomicUsize::new(0),
+            #[cfg(feature = "deadlock-detection")]
+            id: std::sync::atomic::AtomicUsize::new(crate::deadlock::next_id()),
+        }
+    }
+
+    /// for `lock_api`'s `INIT`, loom's atomics can't be made in a const
+    #[cfg(all(feature = "lock_api", not(loom)))]
+    pub(crate) const fn const_new(data: T) -> Self {
+        Self {
+            val: AtomicU64::new(Self::LOCK_FREE),
+            next_ticket: AtomicU32::new(0),
+            serving: AtomicU32::new(0),
             data: UnsafeCell::new(data),
+            poisoned: std::sync::atomic::AtomicBool::new(false),
+            #[cfg(feature = "metrics")]
+            shared_spins: std::sync::atomic::AtomicU64::new(0),
+            #[cfg(feature = "metrics")]
+            exclusive_spins: std::sync::atomic::AtomicU64::new(0),
+            #[cfg(all(feature = "park", not(loom)))]
+            parked: crate::spinmutex::SpinMutex::new(Vec::new()),
+            #[cfg(all(feature = "park", not(loom)))]
+            parked_count: std::sync::atomic::AtomicUsize::new(0),
+            #[cfg(feature = "deadlock-detection")]
+            id: std::sync::atomic::AtomicUsize::new(0),
         }
     }
 }
 
+/// plain shared/exclusive lock with no data, the guards of `Lock<()>` never touch memory
+impl Lock<()> {
+    pub fn new_unit() -> Self {
+        Self::new(())
+    }
+}
+
+impl<T: Default> Default for Lock<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// like `std::sync::Mutex`, prints the data only if it can be read without waiting
+impl<T: std::fmt::Debug> std::fmt::Debug for Lock<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("Lock");
+        match self.try_lock_shared() {
+            Some(g) => d.field("data", &&*g),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
 unsafe impl<T: Send + Sync> Send for Lock<T> {}
-unsafe impl<T: Sync> Sync for Lock<T> {}
+// shared access hands out `&T` to many threads, exclusive access can move a `T`
+// out on another thread, so sharing the lock needs both
+unsafe impl<T: Send + Sync> Sync for Lock<T> {}
 
-#[cfg(test)]
+#[cfg(all(test, not(loom)))]
 mod tests {
     use std::{
         ops::{Deref, DerefMut},
-        sync::mpsc,
+        sync::{
+            atomic::{AtomicBool, AtomicUsize, Ordering},
+            mpsc,
+        },
         thread,
-        time::Duration,
+        time::{Duration, Instant},
     };
 
     use super::Lock;
@@ -262,4 +731,698 @@ mod tests {
             }
         });
     }
+
+    #[test]
+    fn scoped_map() {
+        struct Nested {
+            counter: u32,
+        }
+        struct Outer {
+            name: &'static str,
+            nested: Nested,
+        }
+        let lock = Lock::new(Outer {
+            name: "outer",
+            nested: Nested { counter: 1 },
+        });
+        let old = lock.scoped_map(
+            |o| &mut o.nested.counter,
+            |c| {
+                *c += 41;
+                *c - 41
+            },
+        );
+        assert_eq!(old, 1);
+        let g = lock.lock_shared();
+        assert_eq!(g.nested.counter, 42);
+        assert_eq!(g.name, "outer");
+    }
+
+    #[cfg(feature = "deadlock-detection")]
+    #[test]
+    fn opposite_order_is_flagged() {
+        let a = Lock::new(1);
+        let b = Lock::new(2);
+        // what thread 1 would do
+        {
+            let _ga = a.lock_exclusive();
+            let _gb = b.lock_shared();
+        }
+        // what thread 2 would do
+        let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _gb = b.lock_exclusive();
+            let _ga = a.lock_exclusive();
+        }));
+        assert!(r.is_err());
+        // nothing stays locked after the report
+        drop(a.lock_exclusive());
+        drop(b.lock_exclusive());
+        // the original order is still fine
+        let _ga = a.lock_shared();
+        let _gb = b.lock_shared();
+    }
+
+    #[test]
+    fn exclusive_under_shared_stream() {
+        let lock = Lock::new(0);
+        let stop = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    while !stop.load(Ordering::Relaxed) {
+                        let g = lock.lock_shared();
+                        assert!(*g <= 10);
+                    }
+                });
+            }
+            for _ in 0..10 {
+                thread::sleep(Duration::from_millis(1));
+                let start = Instant::now();
+                *lock.lock_exclusive() += 1;
+                assert!(start.elapsed() < Duration::from_secs(2));
+            }
+            stop.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(*lock.lock_shared(), 10);
+    }
+
+    #[test]
+    fn unit_counter_guard() {
+        let lock = Lock::new_unit();
+        let counter = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        let _g = lock.lock_exclusive();
+                        // not an atomic increment, only correct if the lock excludes
+                        let v = counter.load(Ordering::Relaxed);
+                        counter.store(v + 1, Ordering::Relaxed);
+                    }
+                });
+            }
+        });
+        assert_eq!(counter.load(Ordering::Relaxed), 4000);
+        let () = *lock.lock_shared();
+    }
+
+    #[test]
+    fn downgrade_chain() {
+        let lock = Lock::new(1);
+        let mut g = lock.lock_exclusive();
+        *g = 2;
+        let g = g.downgrade();
+        assert_eq!(lock.val.load(Ordering::Relaxed), 1);
+        thread::scope(|s| {
+            // other readers can join the downgraded guard, writers can't
+            assert_eq!(s.spawn(|| *lock.lock_shared()).join().unwrap(), 2);
+            assert!(lock
+                .val
+                .compare_exchange(
+                    Lock::<i32>::LOCK_FREE,
+                    Lock::<i32>::LOCK_EXCL,
+                    Ordering::Acquire,
+                    Ordering::Relaxed
+                )
+                .is_err());
+        });
+        drop(g);
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+        let mut g = lock.lock_exclusive();
+        *g = 3;
+        let g = g.downgrade().upgrade().downgrade();
+        assert_eq!(*g, 3);
+        drop(g);
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+    }
+
+    #[test]
+    fn scope_shared_upgrade() {
+        let lock = Lock::new(vec![1, 2]);
+        let len = lock.scope_shared(|g| {
+            if g.len() < 3 {
+                let mut g = g.upgrade();
+                g.push(3);
+                return g.len();
+            }
+            g.len()
+        });
+        assert_eq!(len, 3);
+        assert_eq!(
+            lock.val.load(Ordering::Relaxed),
+            Lock::<Vec<i32>>::LOCK_FREE
+        );
+        assert_eq!(*lock.lock_shared(), [1, 2, 3]);
+    }
+
+    #[test]
+    fn reader_count_hint() {
+        let lock = Lock::new(0);
+        let g1 = lock.lock_shared();
+        assert_eq!(g1.reader_count_hint(), 1);
+        let g2 = g1.clone();
+        let g3 = lock.lock_shared();
+        assert_eq!(g1.reader_count_hint(), 3);
+        drop(g2);
+        assert_eq!(g3.reader_count_hint(), 2);
+        drop(g1);
+        assert_eq!(g3.reader_count_hint(), 1);
+    }
+
+    #[test]
+    fn writers_fifo() {
+        const WRITERS: usize = 6;
+        let lock = Lock::new(Vec::new());
+        thread::scope(|s| {
+            let g = lock.lock_exclusive();
+            for i in 0..WRITERS {
+                let lock = &lock;
+                s.spawn(move || lock.lock_exclusive().push(i));
+                // one at a time, so the tickets go out in spawn order
+                while lock.next_ticket.load(Ordering::Relaxed) as usize != i + 2 {
+                    thread::yield_now();
+                }
+            }
+            assert!(!lock.raw_try_lock_exclusive());
+            drop(g);
+        });
+        assert_eq!(*lock.lock_shared(), (0..WRITERS).collect::<Vec<_>>());
+        assert!(lock.raw_try_lock_exclusive());
+        unsafe { lock.raw_unlock_exclusive() };
+    }
+
+    #[test]
+    fn low_spin_limit() {
+        let lock = Lock::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    super::with_test_spin_limit(1, || {
+                        for _ in 0..200 {
+                            *lock.lock_exclusive() += 1;
+                            let _ = *lock.lock_shared();
+                        }
+                    })
+                });
+            }
+        });
+        assert_eq!(*lock.lock_shared(), 800);
+    }
+
+    #[test]
+    fn try_with() {
+        let lock = Lock::new(1);
+        let g = lock.lock_shared();
+        assert_eq!(lock.try_with_exclusive(|v| *v += 1), None);
+        assert_eq!(lock.try_with_shared(|v| *v), Some(1));
+        drop(g);
+        assert_eq!(
+            lock.try_with_exclusive(|v| std::mem::replace(v, 2)),
+            Some(1)
+        );
+        let g = lock.lock_exclusive();
+        assert_eq!(lock.try_with_shared(|v| *v), None);
+        assert_eq!(lock.try_with_exclusive(|v| *v), None);
+        drop(g);
+        assert_eq!(lock.try_with_shared(|v| *v), Some(2));
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+    }
+
+    #[test]
+    fn try_lock() {
+        let lock = Lock::new(1);
+        let g = lock.lock_shared();
+        assert!(lock.try_lock_exclusive().is_none());
+        assert_eq!(*lock.try_lock_shared().unwrap(), 1);
+        drop(g);
+        let mut g = lock.try_lock_exclusive().unwrap();
+        *g = 2;
+        assert!(lock.try_lock_shared().is_none());
+        assert!(lock.try_lock_exclusive().is_none());
+        drop(g);
+        assert_eq!(*lock.try_lock_shared().unwrap(), 2);
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+    }
+
+    #[test]
+    fn modify() {
+        let lock = Lock::new(vec![1, 2]);
+        lock.modify(|v| v.push(3));
+        lock.modify(|v| v.retain(|&el| el != 1));
+        assert_eq!(*lock.lock_shared(), [2, 3]);
+        assert_eq!(
+            lock.val.load(Ordering::Relaxed),
+            Lock::<Vec<i32>>::LOCK_FREE
+        );
+    }
+
+    #[test]
+    fn fetch_update() {
+        let lock = Lock::new(0u64);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..1000 {
+                        lock.fetch_update(|v| Some(v + 1)).unwrap();
+                    }
+                });
+            }
+        });
+        assert_eq!(*lock.lock_shared(), 4000);
+        assert_eq!(lock.fetch_update(|_| None), Err(4000));
+        assert_eq!(lock.fetch_update(|v| Some(v * 2)), Ok(4000));
+        assert_eq!(*lock.lock_shared(), 8000);
+    }
+
+    #[test]
+    fn upgrade_panic_doesnt_leak() {
+        let lock = Lock::new(5);
+        let g = lock.lock_shared();
+        super::raw::PANIC_ON_EXCLUSIVE.with(|p| p.set(true));
+        let r = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| g.upgrade()));
+        assert!(r.is_err());
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+        *lock.lock_exclusive() = 6;
+        let g = lock.lock_shared();
+        assert_eq!(*g, 6);
+        let mut g = g.upgrade();
+        *g = 7;
+        drop(g);
+        assert_eq!(*lock.lock_shared(), 7);
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+    }
+
+    #[test]
+    fn recursive_shared() {
+        let lock = Lock::new(5);
+        let g1 = lock.lock_shared();
+        let g2 = lock.lock_shared();
+        let g3 = g2.clone();
+        assert_eq!(lock.val.load(Ordering::Relaxed), 3);
+        drop(g2);
+        assert_eq!(*g1 + *g3, 10);
+        drop(g1);
+        drop(g3);
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+        drop(lock.lock_exclusive());
+    }
+
+    #[test]
+    fn timeout_while_held() {
+        let lock = Lock::new(1);
+        let g = lock.lock_exclusive();
+        let start = Instant::now();
+        assert!(lock
+            .lock_exclusive_timeout(Duration::from_millis(20))
+            .is_none());
+        assert!(lock
+            .lock_shared_timeout(Duration::from_millis(20))
+            .is_none());
+        assert!(start.elapsed() >= Duration::from_millis(40));
+        drop(g);
+        let g = lock.lock_shared();
+        assert!(lock
+            .lock_exclusive_timeout(Duration::from_millis(20))
+            .is_none());
+        assert_eq!(*lock.lock_shared_timeout(Duration::ZERO).unwrap(), 1);
+        drop(g);
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+    }
+
+    #[test]
+    fn timeout_released_in_time() {
+        let lock = Lock::new(1);
+        thread::scope(|s| {
+            let g = lock.lock_exclusive();
+            let waiter = s.spawn(|| {
+                *lock
+                    .lock_exclusive_timeout(Duration::from_secs(10))
+                    .unwrap() += 1;
+                *lock.lock_shared_timeout(Duration::from_secs(10)).unwrap()
+            });
+            thread::sleep(Duration::from_millis(10));
+            drop(g);
+            assert_eq!(waiter.join().unwrap(), 2);
+        });
+        // far enough out that the deadline can't be represented
+        assert!(lock.lock_exclusive_timeout(Duration::MAX).is_some());
+    }
+
+    #[test]
+    fn map_guards() {
+        struct Pair {
+            a: i32,
+            b: String,
+        }
+        let lock = Lock::new(Pair {
+            a: 1,
+            b: "b".into(),
+        });
+        let b = lock.lock_shared().map(|p| p.b.as_str());
+        assert_eq!(&*b, "b");
+        // still locked for writers, readers can come in
+        assert!(lock.try_lock_exclusive().is_none());
+        assert_eq!(lock.lock_shared().a, 1);
+        drop(b);
+        let mut a = lock.lock_exclusive().map(|p| &mut p.a);
+        *a += 1;
+        assert!(lock.try_lock_shared().is_none());
+        assert!(lock.try_lock_exclusive().is_none());
+        drop(a);
+        assert_eq!(lock.lock_shared().a, 2);
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<Pair>::LOCK_FREE);
+    }
+
+    #[test]
+    fn map_panic_unlocks() {
+        let lock = Lock::new(vec![1]);
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _ = lock.lock_exclusive().map(|v| &mut v[5]);
+        }));
+        assert!(res.is_err());
+        assert_eq!(
+            lock.val.load(Ordering::Relaxed),
+            Lock::<Vec<i32>>::LOCK_FREE
+        );
+    }
+
+    #[test]
+    fn poison() {
+        let lock = Lock::new(vec![1]);
+        thread::scope(|s| {
+            let res = s
+                .spawn(|| {
+                    let mut g = lock.lock_exclusive();
+                    g.push(2);
+                    panic!("halfway through");
+                })
+                .join();
+            assert!(res.is_err());
+        });
+        assert!(lock.is_poisoned());
+        // the plain locks don't care, the data is still there
+        assert_eq!(*lock.lock_shared(), [1, 2]);
+        let err = lock.lock_exclusive_checked().err().unwrap();
+        assert_eq!(*err.into_inner(), [1, 2]);
+        assert!(lock.lock_shared_checked().is_err());
+        lock.clear_poison();
+        assert!(lock.lock_exclusive_checked().is_ok());
+
+        // readers can't leave the data halfway changed, panicking under them doesn't poison
+        let _ = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let _g = lock.lock_shared();
+            panic!("only reading");
+        }));
+        assert!(lock.lock_shared_checked().is_ok());
+
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            let mut g = lock.lock_exclusive().map(|v| &mut v[0]);
+            *g = 5;
+            panic!("mapped");
+        }));
+        assert!(res.is_err());
+        assert!(lock.is_poisoned());
+    }
+
+    #[cfg(feature = "metrics")]
+    #[test]
+    fn spin_stats() {
+        use super::SpinStats;
+
+        let lock = Lock::new(0);
+        *lock.lock_exclusive() += 1;
+        drop(lock.lock_shared());
+        assert_eq!(lock.spin_stats(), SpinStats::default());
+        let started = AtomicBool::new(false);
+        thread::scope(|s| {
+            let g = lock.lock_exclusive();
+            let reader = s.spawn(|| {
+                started.store(true, Ordering::Relaxed);
+                *lock.lock_shared()
+            });
+            while !started.load(Ordering::Relaxed) {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(10));
+            drop(g);
+            reader.join().unwrap();
+        });
+        let after_shared = lock.spin_stats();
+        assert!(after_shared.shared > 0);
+        assert_eq!(after_shared.exclusive, 0);
+        started.store(false, Ordering::Relaxed);
+        thread::scope(|s| {
+            let g = lock.lock_shared();
+            let writer = s.spawn(|| {
+                started.store(true, Ordering::Relaxed);
+                *lock.lock_exclusive() += 1;
+            });
+            while !started.load(Ordering::Relaxed) {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(10));
+            drop(g);
+            writer.join().unwrap();
+        });
+        assert!(lock.spin_stats().exclusive > 0);
+        assert_eq!(lock.spin_stats().shared, after_shared.shared);
+    }
+
+    #[test]
+    fn default_debug() {
+        let lock = Lock::<Vec<i32>>::default();
+        lock.lock_exclusive().push(1);
+        assert_eq!(format!("{lock:?}"), "Lock { data: [1] }");
+        let g = lock.lock_shared();
+        assert_eq!(format!("{lock:?}"), "Lock { data: [1] }");
+        drop(g);
+        let _g = lock.lock_exclusive();
+        assert_eq!(format!("{lock:?}"), "Lock { data: <locked> }");
+    }
+
+    #[test]
+    fn into_inner_get_mut() {
+        struct Counted<'a>(&'a AtomicUsize);
+        impl Drop for Counted<'_> {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        let drops = AtomicUsize::new(0);
+        let mut lock = Lock::new(vec![Counted(&drops)]);
+        lock.get_mut().push(Counted(&drops));
+        lock.lock_exclusive().push(Counted(&drops));
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<()>::LOCK_FREE);
+        let v = lock.into_inner();
+        assert_eq!(v.len(), 3);
+        assert_eq!(drops.load(Ordering::Relaxed), 0);
+        drop(v);
+        assert_eq!(drops.load(Ordering::Relaxed), 3);
+    }
+
+    #[test]
+    fn reader_limit() {
+        let lock = Lock::new(5);
+        let g = lock.lock_shared();
+        // pretend the rest of the readers are in too
+        lock.val.store(Lock::<i32>::MAX_READERS, Ordering::Relaxed);
+        assert!(lock.try_lock_shared().is_none());
+        let (tx, rx) = mpsc::channel();
+        thread::scope(|s| {
+            let lock = &lock;
+            s.spawn(move || {
+                let g = lock.lock_shared();
+                tx.send(*g).unwrap();
+            });
+            assert!(rx.recv_timeout(Duration::from_millis(20)).is_err());
+            // room for exactly one more
+            drop(g);
+            assert_eq!(rx.recv().unwrap(), 5);
+        });
+        assert_eq!(
+            lock.val.load(Ordering::Relaxed),
+            Lock::<i32>::MAX_READERS - 1
+        );
+        lock.val.store(Lock::<i32>::LOCK_FREE, Ordering::Relaxed);
+        assert_eq!(*lock.lock_exclusive(), 5);
+    }
+
+    #[test]
+    fn try_upgrade() {
+        let lock = Lock::new(1);
+        let mut g = lock.lock_shared().try_upgrade().ok().unwrap();
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_EXCL);
+        *g = 2;
+        drop(g);
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+
+        // another reader in means it comes back shared
+        let other = lock.lock_shared();
+        let g = lock.lock_shared().try_upgrade().err().unwrap();
+        assert_eq!(lock.val.load(Ordering::Relaxed), 2);
+        drop(other);
+        let mut g = g.try_upgrade().ok().unwrap();
+        *g = 3;
+        let g = g.downgrade();
+        // and so does a queued writer
+        thread::scope(|s| {
+            s.spawn(|| *lock.lock_exclusive() += 1);
+            while lock.next_ticket.load(Ordering::Relaxed) == lock.serving.load(Ordering::Relaxed) {
+                thread::yield_now();
+            }
+            let g = g.try_upgrade().err().unwrap();
+            assert_eq!(*g, 3);
+        });
+        assert_eq!(*lock.lock_shared(), 4);
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+    }
+
+    #[test]
+    fn writer_not_starved() {
+        const READERS: usize = 8;
+        let lock = Lock::new(0);
+        let done = AtomicBool::new(false);
+        let slowest = thread::scope(|s| {
+            for _ in 0..READERS {
+                s.spawn(|| {
+                    while !done.load(Ordering::Relaxed) {
+                        // hold on across a yield so the readers keep overlapping
+                        let g = lock.lock_shared();
+                        thread::yield_now();
+                        drop(g);
+                    }
+                });
+            }
+            let slowest = (0..20)
+                .map(|_| {
+                    let start = Instant::now();
+                    *lock.lock_exclusive() += 1;
+                    start.elapsed()
+                })
+                .max();
+            done.store(true, Ordering::Relaxed);
+            slowest.unwrap()
+        });
+        assert!(
+            slowest < Duration::from_secs(2),
+            "writer waited {slowest:?}"
+        );
+        assert_eq!(*lock.lock_shared(), 20);
+    }
+
+    #[test]
+    fn recursive_shared_with_waiting_writer() {
+        let lock = Lock::new(5);
+        let waiting = AtomicBool::new(false);
+        thread::scope(|s| {
+            let g1 = lock.lock_shared();
+            let writer = s.spawn(|| {
+                waiting.store(true, Ordering::Relaxed);
+                *lock.lock_exclusive() += 1;
+            });
+            while !waiting.load(Ordering::Relaxed) {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(10));
+            // the writer is spinning on us, taking more shares must still work
+            let g2 = lock.lock_shared();
+            assert_eq!(*g1, *g2);
+            drop(g1);
+            drop(g2);
+            writer.join().unwrap();
+        });
+        assert_eq!(*lock.lock_shared(), 6);
+    }
+}
+
+#[cfg(all(test, loom))]
+mod loom_tests {
+    //! `RUSTFLAGS="--cfg loom" cargo test`, the other tests are left out under loom
+
+    use loom::{cell::UnsafeCell, sync::Arc, thread};
+
+    use super::Lock;
+
+    struct Data(UnsafeCell<u32>);
+
+    // the lock is what makes this sound, which is exactly what loom checks
+    unsafe impl Sync for Data {}
+
+    #[test]
+    fn exclusive_downgrade_drop_exclusive() {
+        loom::model(|| {
+            let lock = Arc::new(Lock::new(Data(UnsafeCell::new(0))));
+            let t = {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    let g = lock.lock_exclusive();
+                    g.0.with_mut(|p| unsafe { *p += 1 });
+                    let g = g.downgrade();
+                    let seen = g.0.with(|p| unsafe { *p });
+                    assert!(seen >= 1);
+                    drop(g);
+                })
+            };
+            {
+                let g = lock.lock_exclusive();
+                g.0.with_mut(|p| unsafe { *p += 1 });
+            }
+            t.join().unwrap();
+            let g = lock.lock_shared();
+            assert_eq!(g.0.with(|p| unsafe { *p }), 2);
+        });
+    }
+
+    #[test]
+    fn two_shared_one_exclusive() {
+        // the second share is taken while holding the first, with the writer maybe pending
+        loom::model(|| {
+            let lock = Arc::new(Lock::new(Data(UnsafeCell::new(0))));
+            let t = {
+                let lock = lock.clone();
+                thread::spawn(move || {
+                    let g1 = lock.lock_shared();
+                    let g2 = lock.lock_shared();
+                    let (a, b) = (g1.0.with(|p| unsafe { *p }), g2.0.with(|p| unsafe { *p }));
+                    // before or after the write, never halfway
+                    assert_eq!(a, b);
+                    a
+                })
+            };
+            lock.lock_exclusive().0.with_mut(|p| unsafe { *p += 1 });
+            assert!(t.join().unwrap() <= 1);
+            assert_eq!(lock.lock_shared().0.with(|p| unsafe { *p }), 1);
+        });
+    }
+
+    #[test]
+    fn upgrade_racing_shared() {
+        loom::model(|| {
+            let lock = Arc::new(Lock::new(Data(UnsafeCell::new(0))));
+            let t = {
+                let lock = lock.clone();
+                thread::spawn(move || lock.lock_shared().0.with(|p| unsafe { *p }))
+            };
+            let g = lock.lock_shared().upgrade();
+            g.0.with_mut(|p| unsafe { *p += 1 });
+            drop(g);
+            assert!(t.join().unwrap() <= 1);
+        });
+    }
+
+    #[test]
+    fn try_upgrade_racing_shared() {
+        loom::model(|| {
+            let lock = Arc::new(Lock::new(Data(UnsafeCell::new(0))));
+            let t = {
+                let lock = lock.clone();
+                thread::spawn(move || lock.lock_shared().0.with(|p| unsafe { *p }))
+            };
+            // either way the other reader can't see a half done write
+            if let Ok(g) = lock.lock_shared().try_upgrade() {
+                g.0.with_mut(|p| unsafe { *p += 1 });
+            }
+            assert!(t.join().unwrap() <= 1);
+        });
+    }
 }
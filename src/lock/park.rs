@@ -0,0 +1,111 @@
+//! the `park` feature: acquires that have spun for `spin_limit` rounds go to sleep
+//! instead of yielding, and every release that could let them in wakes them up.
+//! the `_timeout` locks keep polling, they have a deadline to watch
+
+use std::sync::atomic::{fence, Ordering};
+use std::thread::{self, Thread};
+
+use super::Lock;
+
+impl<T> Lock<T> {
+    /// parks the thread unless `blocked` turns out false once it's queued. it can come back
+    /// without the lock having moved, the callers loop and check again anyway
+    pub(super) fn park_while(&self, blocked: impl Fn(&Self) -> bool) {
+        {
+            let mut parked = self.parked.lock();
+            parked.push(thread::current());
+            self.parked_count.fetch_add(1, Ordering::Relaxed);
+        }
+        // pairs with the fence in `unpark_all`: either the releasing thread sees us
+        // queued, or we see what it released and don't sleep
+        fence(Ordering::SeqCst);
+        if blocked(self) {
+            thread::park();
+        }
+        // if we didn't sleep we stay queued, the next release hands us a token we don't need
+    }
+
+    /// wakes everyone parked, after a change to `val` or `serving` that lets someone in.
+    /// all of them rather than the next in line, they go back to the queue if they lose
+    pub(super) fn unpark_all(&self) {
+        fence(Ordering::SeqCst);
+        if self.parked_count.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let woken: Vec<Thread> = {
+            let mut parked = self.parked.lock();
+            self.parked_count.fetch_sub(parked.len(), Ordering::Relaxed);
+            parked.drain(..).collect()
+        };
+        for t in woken {
+            t.unpark();
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use crate::lock::Lock;
+
+    /// this thread's user + system time in clock ticks
+    #[cfg(target_os = "linux")]
+    fn cpu_ticks() -> u64 {
+        let stat = std::fs::read_to_string("/proc/thread-self/stat").unwrap();
+        // the command name can have spaces in it, the fields after it don't
+        let fields: Vec<&str> = stat[stat.rfind(')').unwrap() + 2..].split(' ').collect();
+        fields[11].parse::<u64>().unwrap() + fields[12].parse::<u64>().unwrap()
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn long_held_lock_doesnt_burn_cpu() {
+        let lock = Lock::new(0);
+        let started = AtomicBool::new(false);
+        thread::scope(|s| {
+            let g = lock.lock_exclusive();
+            let waiter = s.spawn(|| {
+                let before = cpu_ticks();
+                started.store(true, Ordering::Relaxed);
+                let seen = *lock.lock_shared();
+                (seen, cpu_ticks() - before)
+            });
+            while !started.load(Ordering::Relaxed) {
+                thread::yield_now();
+            }
+            // a yielding waiter would take most of this, on a single core all of it
+            thread::sleep(Duration::from_millis(300));
+            drop(g);
+            let (seen, ticks) = waiter.join().unwrap();
+            assert_eq!(seen, 0);
+            // ticks are usually 10ms. a waiter that keeps spinning or yielding gets
+            // most of the 300ms, loaded machines included, a parked one a tick or two
+            assert!(ticks < 10, "waiter used {ticks} ticks of cpu");
+        });
+    }
+
+    #[test]
+    fn parked_writers_all_get_in() {
+        let lock = Lock::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                // parks on the first round that finds it taken
+                s.spawn(|| {
+                    crate::lock::with_test_spin_limit(0, || {
+                        for _ in 0..200 {
+                            *lock.lock_exclusive() += 1;
+                            drop(lock.lock_shared());
+                        }
+                    })
+                });
+            }
+        });
+        assert_eq!(*lock.lock_shared(), 800);
+        assert_eq!(lock.parked_count.load(Ordering::Relaxed), 0);
+    }
+}
@@ -0,0 +1,342 @@
+//! locking without RAII guards, for when the lock's lifetime can't follow a scope
+//! (e.g. it's taken in one FFI callback and released in another).
+//! the guard-based API is built on top of these, so both can be mixed on the same lock
+
+use super::Lock;
+use crate::sync::{atomic::Ordering, backoff, spin_hint};
+
+#[cfg(test)]
+thread_local! {
+    /// makes the next `raw_lock_exclusive` on this thread panic, for testing the callers' unwinding
+    pub(crate) static PANIC_ON_EXCLUSIVE: std::cell::Cell<bool> = const { std::cell::Cell::new(false) };
+}
+
+impl<T> Lock<T> {
+    #[inline]
+    pub fn raw_lock_shared(&self) {
+        #[cfg(feature = "deadlock-detection")]
+        crate::deadlock::acquire(self.deadlock_id());
+        // uncontended, nobody else is in
+        if self
+            .val
+            .compare_exchange_weak(Self::LOCK_FREE, 1, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            self.lock_shared_slow();
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn lock_shared_slow(&self) {
+        let mut current = self.val.load(Ordering::Relaxed);
+        let spin_limit = super::spin_limit();
+        let mut excl_spins = 0;
+        let mut pending_spins = 0;
+        // loom runs out of branches holding back for long, what it checks is the same either way
+        let patience = if cfg!(loom) { 1 } else { spin_limit * 4 };
+        loop {
+            if current == Self::LOCK_EXCL {
+                // the writer might be about to release, give it the core
+                // instead of hammering the cache line
+                self.wait(&mut excl_spins, spin_limit, |l| {
+                    l.val.load(Ordering::Relaxed) == Self::LOCK_EXCL
+                });
+                current = Self::LOCK_FREE;
+                continue;
+            }
+            // a writer is next, let the readers drain. only for so long though,
+            // this thread may hold a share already and that's what the writer waits on
+            if current & Self::LOCK_PENDING != 0 && pending_spins < patience {
+                backoff(&mut pending_spins, spin_limit);
+                current = self.val.load(Ordering::Relaxed);
+                continue;
+            }
+            // one more would carry into the pending bit, wait for someone to leave
+            if current & Self::READERS_MASK == Self::MAX_READERS {
+                backoff(&mut excl_spins, spin_limit);
+                current = self.val.load(Ordering::Relaxed);
+                continue;
+            }
+            match self.val.compare_exchange_weak(
+                current,
+                current + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(actual) => {
+                    current = actual;
+                    spin_hint();
+                }
+            }
+        }
+        // once per slow acquire, adding every round would be contention of its own
+        #[cfg(feature = "metrics")]
+        self.shared_spins.fetch_add(
+            (excl_spins + pending_spins) as u64,
+            std::sync::atomic::Ordering::Relaxed,
+        );
+    }
+
+    /// `false` right away if there's a writer, or one waiting for its turn,
+    /// or there are already `MAX_READERS`
+    pub fn raw_try_lock_shared(&self) -> bool {
+        let mut current = self.val.load(Ordering::Relaxed);
+        while current & (Self::LOCK_EXCL | Self::LOCK_PENDING) == 0 && current != Self::MAX_READERS
+        {
+            match self.val.compare_exchange(
+                current,
+                current + 1,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => {
+                    #[cfg(feature = "deadlock-detection")]
+                    crate::deadlock::acquired(self.deadlock_id());
+                    return true;
+                }
+                // another reader came or left, that doesn't stop us
+                Err(actual) => current = actual,
+            }
+        }
+        false
+    }
+
+    /// another share for a thread that already has one, so no waiting on a
+    /// pending writer. only the reader limit can hold it up
+    pub(super) fn add_share(&self) {
+        let spin_limit = super::spin_limit();
+        let mut spins = 0;
+        while self
+            .val
+            .fetch_update(Ordering::Acquire, Ordering::Relaxed, |v| {
+                (v & Self::READERS_MASK != Self::MAX_READERS).then_some(v + 1)
+            })
+            .is_err()
+        {
+            backoff(&mut spins, spin_limit);
+        }
+        #[cfg(feature = "metrics")]
+        self.shared_spins
+            .fetch_add(spins as u64, std::sync::atomic::Ordering::Relaxed);
+    }
+
+    /// # Safety
+    /// the calling code must own a shared lock taken with `raw_lock_shared`
+    /// (or a guard it `forget`-ed), and gives it up here
+    #[inline]
+    pub unsafe fn raw_unlock_shared(&self) {
+        // release, so the reads done under the shared lock happen before the next writer
+        self.val.fetch_sub(1, Ordering::Release);
+        self.wake_waiters();
+        #[cfg(feature = "deadlock-detection")]
+        crate::deadlock::release(self.deadlock_id());
+    }
+
+    #[inline]
+    pub fn raw_lock_exclusive(&self) {
+        #[cfg(test)]
+        if PANIC_ON_EXCLUSIVE.with(|p| p.replace(false)) {
+            panic!("injected lock_exclusive panic");
+        }
+        #[cfg(feature = "deadlock-detection")]
+        crate::deadlock::acquire(self.deadlock_id());
+        // writers line up by ticket so none of them can be starved by the others,
+        // then the one whose turn it is waits for the readers to leave
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        if self.serving.load(Ordering::Acquire) != ticket
+            || self
+                .val
+                .compare_exchange_weak(
+                    Self::LOCK_FREE,
+                    Self::LOCK_EXCL,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            self.lock_exclusive_slow(ticket);
+        }
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn lock_exclusive_slow(&self, ticket: u32) {
+        let spin_limit = super::spin_limit();
+        let mut spins = 0;
+        while self.serving.load(Ordering::Acquire) != ticket {
+            self.wait(&mut spins, spin_limit, |l| {
+                l.serving.load(Ordering::Relaxed) != ticket
+            });
+        }
+        // our turn, nobody else sets or clears the pending bit until we're done
+        let mut current = self.val.load(Ordering::Relaxed);
+        loop {
+            if current & Self::READERS_MASK == 0 {
+                match self.val.compare_exchange_weak(
+                    current,
+                    Self::LOCK_EXCL,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => {
+                        #[cfg(feature = "metrics")]
+                        self.exclusive_spins
+                            .fetch_add(spins as u64, std::sync::atomic::Ordering::Relaxed);
+                        return;
+                    }
+                    Err(actual) => current = actual,
+                }
+                continue;
+            }
+            if current & Self::LOCK_PENDING == 0 {
+                current =
+                    self.val.fetch_or(Self::LOCK_PENDING, Ordering::Relaxed) | Self::LOCK_PENDING;
+                continue;
+            }
+            self.wait(&mut spins, spin_limit, |l| {
+                l.val.load(Ordering::Relaxed) & Self::READERS_MASK != 0
+            });
+            current = self.val.load(Ordering::Relaxed);
+        }
+    }
+
+    /// `false` right away if anyone holds the lock or a writer is queued for it
+    pub fn raw_try_lock_exclusive(&self) -> bool {
+        let serving = self.serving.load(Ordering::Relaxed);
+        // only take a ticket if it's served right away
+        if self
+            .next_ticket
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_err()
+        {
+            return false;
+        }
+        let locked = self
+            .val
+            .compare_exchange(
+                Self::LOCK_FREE,
+                Self::LOCK_EXCL,
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .is_ok();
+        if !locked {
+            // readers are in, pass the turn on as if we'd locked and unlocked
+            self.serving.fetch_add(1, Ordering::Release);
+            self.wake_waiters();
+            return false;
+        }
+        #[cfg(feature = "deadlock-detection")]
+        crate::deadlock::acquired(self.deadlock_id());
+        true
+    }
+
+    /// # Safety
+    /// the calling code must own the exclusive lock taken with `raw_lock_exclusive`
+    /// (or a guard it `forget`-ed), and gives it up here
+    #[inline]
+    pub unsafe fn raw_unlock_exclusive(&self) {
+        self.val.store(Self::LOCK_FREE, Ordering::Release);
+        self.serving.fetch_add(1, Ordering::Release);
+        self.wake_waiters();
+        #[cfg(feature = "deadlock-detection")]
+        crate::deadlock::release(self.deadlock_id());
+    }
+
+    /// one round of `backoff` while `blocked` holds. with the `park` feature the thread
+    /// sleeps instead of yielding once `spin_limit` rounds are used up
+    #[inline]
+    fn wait(&self, spins: &mut u32, spin_limit: u32, blocked: impl Fn(&Self) -> bool) {
+        #[cfg(all(feature = "park", not(loom)))]
+        if *spins >= spin_limit {
+            *spins += 1;
+            self.park_while(blocked);
+            return;
+        }
+        #[cfg(not(all(feature = "park", not(loom))))]
+        let _ = blocked;
+        backoff(spins, spin_limit);
+    }
+
+    /// after every change that could let a waiting acquire in, nothing without `park`
+    #[inline]
+    pub(super) fn wake_waiters(&self) {
+        #[cfg(all(feature = "park", not(loom)))]
+        self.unpark_all();
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::sync::atomic::Ordering;
+
+    use crate::lock::Lock;
+
+    #[test]
+    fn shared_transitions() {
+        let lock = Lock::new(5);
+        lock.raw_lock_shared();
+        assert_eq!(lock.val.load(Ordering::Relaxed), 1);
+        let g = lock.lock_shared();
+        assert_eq!(lock.val.load(Ordering::Relaxed), 2);
+        lock.raw_lock_shared();
+        assert_eq!(lock.val.load(Ordering::Relaxed), 3);
+        drop(g);
+        assert_eq!(lock.val.load(Ordering::Relaxed), 2);
+        unsafe {
+            lock.raw_unlock_shared();
+            lock.raw_unlock_shared();
+        }
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+    }
+
+    #[test]
+    fn exclusive_transitions() {
+        let lock = Lock::new(5);
+        lock.raw_lock_exclusive();
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_EXCL);
+        unsafe { lock.raw_unlock_exclusive() };
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+        let g = lock.lock_exclusive();
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_EXCL);
+        drop(g);
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+    }
+
+    #[test]
+    fn try_transitions() {
+        let lock = Lock::new(5);
+        assert!(lock.raw_try_lock_shared());
+        assert!(lock.raw_try_lock_shared());
+        assert_eq!(lock.val.load(Ordering::Relaxed), 2);
+        assert!(!lock.raw_try_lock_exclusive());
+        unsafe {
+            lock.raw_unlock_shared();
+            lock.raw_unlock_shared();
+        }
+        assert!(lock.raw_try_lock_exclusive());
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_EXCL);
+        assert!(!lock.raw_try_lock_shared());
+        assert!(!lock.raw_try_lock_exclusive());
+        unsafe { lock.raw_unlock_exclusive() };
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_FREE);
+    }
+
+    #[test]
+    fn guard_released_raw() {
+        let lock = Lock::new(5);
+        let mut g = lock.lock_exclusive();
+        *g = 6;
+        std::mem::forget(g);
+        assert_eq!(lock.val.load(Ordering::Relaxed), Lock::<i32>::LOCK_EXCL);
+        unsafe { lock.raw_unlock_exclusive() };
+        assert_eq!(*lock.lock_shared(), 6);
+    }
+}
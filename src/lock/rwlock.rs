@@ -0,0 +1,87 @@
+//! `Lock`'s state machine plugged into `lock_api`, so its `RwLock` and friends
+//! (mapped guards, arc guards, ...) run on top of it
+
+use super::Lock;
+
+/// a data-less `Lock`, only the state word. the data lives in the `lock_api::RwLock`
+pub struct RawLock {
+    lock: Lock<()>,
+}
+
+pub type RwLock<T> = lock_api::RwLock<RawLock, T>;
+pub type RwLockReadGuard<'a, T> = lock_api::RwLockReadGuard<'a, RawLock, T>;
+pub type RwLockWriteGuard<'a, T> = lock_api::RwLockWriteGuard<'a, RawLock, T>;
+
+unsafe impl lock_api::RawRwLock for RawLock {
+    const INIT: Self = RawLock {
+        lock: Lock::const_new(()),
+    };
+
+    // unlocking from another thread is fine, it's just an atomic
+    type GuardMarker = lock_api::GuardSend;
+
+    fn lock_shared(&self) {
+        self.lock.raw_lock_shared();
+    }
+
+    fn try_lock_shared(&self) -> bool {
+        self.lock.raw_try_lock_shared()
+    }
+
+    unsafe fn unlock_shared(&self) {
+        self.lock.raw_unlock_shared();
+    }
+
+    fn lock_exclusive(&self) {
+        self.lock.raw_lock_exclusive();
+    }
+
+    fn try_lock_exclusive(&self) -> bool {
+        self.lock.raw_try_lock_exclusive()
+    }
+
+    unsafe fn unlock_exclusive(&self) {
+        self.lock.raw_unlock_exclusive();
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::thread;
+
+    use super::{RwLock, RwLockWriteGuard};
+
+    static GLOBAL: RwLock<Vec<i32>> = RwLock::new(Vec::new());
+
+    #[test]
+    fn read_write() {
+        let lock = RwLock::new(5);
+        {
+            let r1 = lock.read();
+            let r2 = lock.read();
+            assert_eq!(*r1 + *r2, 10);
+            assert!(lock.try_write().is_none());
+        }
+        *lock.write() += 1;
+        let w = lock.write();
+        assert!(lock.try_read().is_none());
+        // the lock_api extras come along for free
+        let mut mapped = RwLockWriteGuard::map(w, |v| v);
+        *mapped += 1;
+        drop(mapped);
+        assert_eq!(*lock.read(), 7);
+        assert_eq!(lock.into_inner(), 7);
+    }
+
+    #[test]
+    fn static_init() {
+        thread::scope(|s| {
+            for i in 0..4 {
+                s.spawn(move || GLOBAL.write().push(i));
+            }
+        });
+        let mut seen = GLOBAL.read().clone();
+        seen.sort();
+        assert_eq!(seen, [0, 1, 2, 3]);
+    }
+}
@@ -1,7 +1,7 @@
 use std::thread;
 
 fn main() {
-    let avec = rst_test::AVec::new(10);
+    let avec = rst_test::AVec::with_capacity(10);
     thread::scope(|s| {
         s.spawn(|| {
             avec.push(2);
@@ -0,0 +1,145 @@
+use std::cell::UnsafeCell;
+use std::sync::atomic::{fence, AtomicUsize, Ordering};
+
+use crate::sync::backoff;
+
+/// sequence lock for small `Copy` data: readers never write anything, they copy the value
+/// out and retry if a writer got in the way. the version is odd while someone's writing
+pub struct SeqLock<T: Copy> {
+    seq: AtomicUsize,
+    data: UnsafeCell<T>,
+}
+
+impl<T: Copy> SeqLock<T> {
+    pub const fn new(data: T) -> Self {
+        Self {
+            seq: AtomicUsize::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    /// a consistent copy, spins while a write is in progress
+    pub fn read(&self) -> T {
+        let spin_limit = crate::lock::spin_limit();
+        let mut spins = 0;
+        loop {
+            let before = self.seq.load(Ordering::Acquire);
+            if before & 1 == 0 {
+                // may race with a writer, which is why it's volatile and only kept if
+                // the version didn't move
+                let value = unsafe { std::ptr::read_volatile(self.data.get()) };
+                fence(Ordering::Acquire);
+                if self.seq.load(Ordering::Relaxed) == before {
+                    return value;
+                }
+            }
+            backoff(&mut spins, spin_limit);
+        }
+    }
+
+    pub fn write(&self, value: T) {
+        self.update(|_| value);
+    }
+
+    /// `f` gets the current value and returns the new one, writers go one at a time
+    pub fn update(&self, f: impl FnOnce(T) -> T) {
+        let spin_limit = crate::lock::spin_limit();
+        let mut spins = 0;
+        let seq = loop {
+            let seq = self.seq.load(Ordering::Relaxed);
+            if seq & 1 == 0
+                && self
+                    .seq
+                    .compare_exchange_weak(seq, seq + 1, Ordering::Acquire, Ordering::Relaxed)
+                    .is_ok()
+            {
+                break seq;
+            }
+            backoff(&mut spins, spin_limit);
+        };
+        // the odd version has to be visible before any of the new data
+        fence(Ordering::Release);
+        // back to even on the way out, also if `f` panics. the old value is still
+        // in place then since `f` never got to return the new one
+        let _done = EndWrite {
+            seq: &self.seq,
+            next: seq + 2,
+        };
+        unsafe {
+            let ptr = self.data.get();
+            std::ptr::write_volatile(ptr, f(std::ptr::read(ptr)));
+        }
+    }
+}
+
+struct EndWrite<'a> {
+    seq: &'a AtomicUsize,
+    next: usize,
+}
+
+impl Drop for EndWrite<'_> {
+    fn drop(&mut self) {
+        self.seq.store(self.next, Ordering::Release);
+    }
+}
+
+unsafe impl<T: Copy + Send> Send for SeqLock<T> {}
+unsafe impl<T: Copy + Send> Sync for SeqLock<T> {}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        thread,
+    };
+
+    use super::SeqLock;
+
+    #[test]
+    fn no_torn_reads() {
+        // every write keeps all the words equal, a torn read would mix two writes
+        let lock = SeqLock::new([0u64; 8]);
+        let done = AtomicBool::new(false);
+        thread::scope(|s| {
+            for _ in 0..2 {
+                s.spawn(|| {
+                    for _ in 0..2000 {
+                        lock.update(|v| [v[0] + 1; 8]);
+                    }
+                });
+            }
+            for _ in 0..2 {
+                s.spawn(|| {
+                    let mut last = 0;
+                    while !done.load(Ordering::Relaxed) {
+                        let v = lock.read();
+                        assert!(v.iter().all(|&w| w == v[0]), "torn read {v:?}");
+                        assert!(v[0] >= last);
+                        last = v[0];
+                    }
+                });
+            }
+            // the readers run until both writers are through
+            while lock.read()[0] != 4000 {
+                thread::yield_now();
+            }
+            done.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(lock.read(), [4000; 8]);
+        lock.write([1; 8]);
+        assert_eq!(lock.read(), [1; 8]);
+    }
+
+    #[test]
+    fn panicking_update() {
+        let lock = SeqLock::new((1, 1));
+        let res = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| {
+            lock.update(|_| panic!("halfway through"));
+        }));
+        assert!(res.is_err());
+        // not stuck on an odd version, and the old value is still there
+        assert_eq!(lock.read(), (1, 1));
+        lock.update(|(a, b)| (a + 1, b + 1));
+        assert_eq!(lock.read(), (2, 2));
+    }
+}
@@ -1,43 +1,155 @@
 use std::{
     cell::UnsafeCell,
     ops::{Deref, DerefMut},
-    sync::atomic::{AtomicBool, Ordering},
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        LockResult, PoisonError,
+    },
 };
 
+use crate::lock::Lock;
+use crate::sync::backoff;
+
 pub struct SpinMutex<T> {
     locked: AtomicBool,
+    /// set when a guard is dropped by a panic, only `lock_checked` looks at it
+    poisoned: AtomicBool,
     data: UnsafeCell<T>,
 }
 
 impl<T: Sync + Send> SpinMutex<T> {
+    #[inline]
     pub fn lock(&self) -> SpinMutexGuard<'_, T> {
+        if self.locked.swap(true, Ordering::Acquire) {
+            self.lock_slow();
+        }
+        SpinMutexGuard::new(self)
+    }
+
+    #[cold]
+    #[inline(never)]
+    fn lock_slow(&self) {
+        let spin_limit = crate::lock::spin_limit();
+        let mut spins = 0;
         loop {
+            // only read while it's taken, the swap would pull the line over
+            // to this core every time
+            while self.locked.load(Ordering::Relaxed) {
+                backoff(&mut spins, spin_limit);
+            }
             if !self.locked.swap(true, Ordering::Acquire) {
-                break;
-            } else {
-                std::hint::spin_loop();
+                return;
             }
         }
-        SpinMutexGuard { mt: self }
     }
 
-    pub fn new(data: T) -> Self {
+    /// one attempt, `None` if someone has it
+    pub fn try_lock(&self) -> Option<SpinMutexGuard<'_, T>> {
+        if self.locked.swap(true, Ordering::Acquire) {
+            return None;
+        }
+        Some(SpinMutexGuard::new(self))
+    }
+
+    /// `lock`, but `Err` if someone panicked while holding it, like `std::sync::Mutex::lock`
+    pub fn lock_checked(&self) -> LockResult<SpinMutexGuard<'_, T>> {
+        let guard = self.lock();
+        if self.is_poisoned() {
+            Err(PoisonError::new(guard))
+        } else {
+            Ok(guard)
+        }
+    }
+
+    pub fn is_poisoned(&self) -> bool {
+        self.poisoned.load(Ordering::Relaxed)
+    }
+
+    /// for when the data has been checked or fixed up after a panic
+    pub fn clear_poison(&self) {
+        self.poisoned.store(false, Ordering::Relaxed);
+    }
+
+    /// a racy look at the flag, it can be stale by the time the caller acts on it.
+    /// good for stats and heuristics, never for deciding whether it's safe to touch the data
+    pub fn is_locked(&self) -> bool {
+        self.locked.load(Ordering::Relaxed)
+    }
+
+    /// same as `lock`, plus whether the first attempt found the mutex taken
+    pub fn lock_hint(&self) -> (SpinMutexGuard<'_, T>, bool) {
+        if !self.locked.swap(true, Ordering::Acquire) {
+            return (SpinMutexGuard::new(self), false);
+        }
+        (self.lock(), true)
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// no locking needed, `&mut` already rules out everyone else
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+
+    /// moves the data into a `Lock`, for when readers should be able to share it
+    pub fn into_rwlock(self) -> Lock<T> {
+        Lock::new(self.data.into_inner())
+    }
+
+    pub const fn new(data: T) -> Self {
         Self {
             locked: AtomicBool::new(false),
+            poisoned: AtomicBool::new(false),
             data: UnsafeCell::new(data),
         }
     }
 }
 
+impl<T: Default + Send + Sync> Default for SpinMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+/// like `std::sync::Mutex`, prints the data only if it's free right now
+impl<T: std::fmt::Debug + Send + Sync> std::fmt::Debug for SpinMutex<T> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let mut d = f.debug_struct("SpinMutex");
+        match self.try_lock() {
+            Some(g) => d.field("data", &&*g),
+            None => d.field("data", &format_args!("<locked>")),
+        };
+        d.finish()
+    }
+}
+
 unsafe impl<T: Send + Sync> Send for SpinMutex<T> {}
-unsafe impl<T: Sync> Sync for SpinMutex<T> {}
+unsafe impl<T: Send + Sync> Sync for SpinMutex<T> {}
 
 pub struct SpinMutexGuard<'a, T> {
     mt: &'a SpinMutex<T>,
+    /// already unwinding when the lock was taken, that panic didn't happen under it
+    panicking: bool,
+}
+
+impl<'a, T> SpinMutexGuard<'a, T> {
+    fn new(mt: &'a SpinMutex<T>) -> Self {
+        Self {
+            mt,
+            panicking: std::thread::panicking(),
+        }
+    }
 }
 
 impl<'a, T> Drop for SpinMutexGuard<'a, T> {
+    #[inline]
     fn drop(&mut self) {
+        // the release below publishes it to the next holder
+        if !self.panicking && std::thread::panicking() {
+            self.mt.poisoned.store(true, Ordering::Relaxed);
+        }
         self.mt.locked.store(false, Ordering::Release);
     }
 }
@@ -55,11 +167,105 @@ impl<'a, T> DerefMut for SpinMutexGuard<'a, T> {
     }
 }
 
-#[cfg(test)]
+/// ticket lock: everyone takes a number and waits for it to come up, so the mutex
+/// goes around in the order it was asked for. `SpinMutex` is cheaper when nobody waits
+pub struct TicketMutex<T> {
+    next_ticket: AtomicU32,
+    now_serving: AtomicU32,
+    data: UnsafeCell<T>,
+}
+
+impl<T: Sync + Send> TicketMutex<T> {
+    pub fn new(data: T) -> Self {
+        Self {
+            next_ticket: AtomicU32::new(0),
+            now_serving: AtomicU32::new(0),
+            data: UnsafeCell::new(data),
+        }
+    }
+
+    pub fn lock(&self) -> TicketMutexGuard<'_, T> {
+        let ticket = self.next_ticket.fetch_add(1, Ordering::Relaxed);
+        let spin_limit = crate::lock::spin_limit();
+        let mut spins = 0;
+        while self.now_serving.load(Ordering::Acquire) != ticket {
+            backoff(&mut spins, spin_limit);
+        }
+        TicketMutexGuard { mt: self }
+    }
+
+    /// only takes a ticket if it would be served right away, `None` if anyone
+    /// holds it or is queued
+    pub fn try_lock(&self) -> Option<TicketMutexGuard<'_, T>> {
+        let serving = self.now_serving.load(Ordering::Acquire);
+        self.next_ticket
+            .compare_exchange(
+                serving,
+                serving.wrapping_add(1),
+                Ordering::Acquire,
+                Ordering::Relaxed,
+            )
+            .ok()
+            .map(|_| TicketMutexGuard { mt: self })
+    }
+
+    pub fn into_inner(self) -> T {
+        self.data.into_inner()
+    }
+
+    /// no locking needed, `&mut` already rules out everyone else
+    pub fn get_mut(&mut self) -> &mut T {
+        self.data.get_mut()
+    }
+}
+
+impl<T: Default + Send + Sync> Default for TicketMutex<T> {
+    fn default() -> Self {
+        Self::new(T::default())
+    }
+}
+
+unsafe impl<T: Send + Sync> Send for TicketMutex<T> {}
+unsafe impl<T: Send + Sync> Sync for TicketMutex<T> {}
+
+pub struct TicketMutexGuard<'a, T> {
+    mt: &'a TicketMutex<T>,
+}
+
+impl<'a, T> Drop for TicketMutexGuard<'a, T> {
+    #[inline]
+    fn drop(&mut self) {
+        // only the holder moves it, the next ticket in line sees the release
+        self.mt.now_serving.fetch_add(1, Ordering::Release);
+    }
+}
+
+impl<'a, T> Deref for TicketMutexGuard<'a, T> {
+    type Target = T;
+
+    fn deref(&self) -> &Self::Target {
+        unsafe { &*self.mt.data.get() }
+    }
+}
+impl<'a, T> DerefMut for TicketMutexGuard<'a, T> {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        unsafe { &mut *self.mt.data.get() }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
 mod tests {
-    use std::{sync::mpsc, thread, time::Duration};
+    use std::{
+        sync::{
+            atomic::{AtomicBool, Ordering},
+            mpsc,
+        },
+        thread,
+        time::Duration,
+    };
 
-    use super::SpinMutex;
+    use super::{SpinMutex, TicketMutex};
+    use crate::lock::Lock;
 
     #[test]
     fn should_unlock() {
@@ -86,4 +292,174 @@ mod tests {
         });
         assert!(rx.recv_timeout(Duration::from_millis(10)).is_err());
     }
+
+    #[test]
+    fn lock_hint() {
+        let m = SpinMutex::new(5);
+        let (g, contended) = m.lock_hint();
+        assert!(!contended);
+        drop(g);
+        let started = AtomicBool::new(false);
+        thread::scope(|s| {
+            let g = m.lock();
+            let waiter = s.spawn(|| {
+                started.store(true, Ordering::Relaxed);
+                m.lock_hint().1
+            });
+            while !started.load(Ordering::Relaxed) {
+                thread::yield_now();
+            }
+            thread::sleep(Duration::from_millis(10));
+            drop(g);
+            assert!(waiter.join().unwrap());
+        });
+    }
+
+    #[test]
+    fn try_lock_held_elsewhere() {
+        let m = SpinMutex::new(5);
+        let (locked_tx, locked_rx) = mpsc::channel();
+        let (done_tx, done_rx) = mpsc::channel::<()>();
+        thread::scope(|s| {
+            let m = &m;
+            s.spawn(move || {
+                let _g = m.lock();
+                locked_tx.send(()).unwrap();
+                done_rx.recv().unwrap();
+            });
+            locked_rx.recv().unwrap();
+            assert!(m.try_lock().is_none());
+            done_tx.send(()).unwrap();
+        });
+        *m.try_lock().unwrap() += 1;
+        assert_eq!(*m.lock(), 6);
+    }
+
+    #[test]
+    fn try_lock_after_drop() {
+        let m = SpinMutex::new(5);
+        let g = m.try_lock().unwrap();
+        assert!(m.try_lock().is_none());
+        drop(g);
+        assert!(m.try_lock().is_some());
+    }
+
+    #[test]
+    fn contended() {
+        // runs the acquire loops with and without the `no-spin-hint` feature
+        let m = SpinMutex::new(0);
+        let lock = Lock::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..500 {
+                        *m.lock() += 1;
+                        *lock.lock_exclusive() += 1;
+                        let _ = *lock.lock_shared();
+                    }
+                });
+            }
+        });
+        assert_eq!(*m.lock(), 2000);
+        assert_eq!(*lock.lock_shared(), 2000);
+    }
+
+    #[test]
+    fn is_locked() {
+        let m = SpinMutex::new(5);
+        assert!(!m.is_locked());
+        let g = m.lock();
+        assert!(m.is_locked());
+        drop(g);
+        assert!(!m.is_locked());
+    }
+
+    #[test]
+    fn poison() {
+        let m = SpinMutex::new(0);
+        thread::scope(|s| {
+            let res = s
+                .spawn(|| {
+                    *m.lock() += 1;
+                    let _g = m.lock_checked().unwrap();
+                    panic!("halfway through");
+                })
+                .join();
+            assert!(res.is_err());
+        });
+        assert!(m.is_poisoned());
+        assert_eq!(*m.lock(), 1);
+        assert_eq!(*m.lock_checked().err().unwrap().into_inner(), 1);
+        m.clear_poison();
+        assert!(m.lock_checked().is_ok());
+    }
+
+    #[test]
+    fn default_debug() {
+        let m = SpinMutex::<u32>::default();
+        *m.lock() += 7;
+        assert_eq!(format!("{m:?}"), "SpinMutex { data: 7 }");
+        let _g = m.lock();
+        assert_eq!(format!("{m:?}"), "SpinMutex { data: <locked> }");
+    }
+
+    #[test]
+    fn into_inner_get_mut() {
+        let mut m = SpinMutex::new(vec![1]);
+        m.get_mut().push(2);
+        // locking still works in between, `get_mut` never left it locked
+        m.lock().push(3);
+        m.get_mut().push(4);
+        assert_eq!(m.into_inner(), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn into_rwlock() {
+        let m = SpinMutex::new(vec![1, 2, 3]);
+        m.lock().push(4);
+        let lock = m.into_rwlock();
+        let g1 = lock.lock_shared();
+        let g2 = lock.lock_shared();
+        assert_eq!(*g1, [1, 2, 3, 4]);
+        assert_eq!(g1.len(), g2.len());
+    }
+
+    #[test]
+    fn ticket_order() {
+        const THREAD_COUNT: u32 = 6;
+        let m = TicketMutex::new(Vec::new());
+        thread::scope(|s| {
+            let g = m.lock();
+            for t in 0..THREAD_COUNT {
+                let m = &m;
+                s.spawn(move || m.lock().push(t));
+                // the next one only starts once this one holds a ticket
+                while m.next_ticket.load(Ordering::Relaxed) != t + 2 {
+                    thread::yield_now();
+                }
+            }
+            assert!(m.try_lock().is_none());
+            drop(g);
+        });
+        assert_eq!(*m.lock(), (0..THREAD_COUNT).collect::<Vec<_>>());
+        assert!(m.try_lock().is_some());
+        let mut m = m;
+        m.get_mut().clear();
+        assert!(m.into_inner().is_empty());
+    }
+
+    #[test]
+    fn ticket_contended() {
+        let m = TicketMutex::new(0);
+        thread::scope(|s| {
+            for _ in 0..4 {
+                s.spawn(|| {
+                    for _ in 0..500 {
+                        *m.lock() += 1;
+                    }
+                });
+            }
+        });
+        assert_eq!(*m.lock(), 2000);
+    }
 }
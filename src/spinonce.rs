@@ -0,0 +1,118 @@
+use std::sync::atomic::{AtomicU8, Ordering};
+
+use crate::sync::backoff;
+
+const INCOMPLETE: u8 = 0;
+const RUNNING: u8 = 1;
+const COMPLETE: u8 = 2;
+
+pub struct SpinOnce {
+    state: AtomicU8,
+}
+
+impl SpinOnce {
+    pub const fn new() -> Self {
+        Self {
+            state: AtomicU8::new(INCOMPLETE),
+        }
+    }
+
+    /// runs `f` once across all threads, the others spin until it's done.
+    /// if `f` panics the next caller gets to run its own `f`
+    pub fn call_once(&self, f: impl FnOnce()) {
+        let spin_limit = crate::lock::spin_limit();
+        let mut spins = 0;
+        loop {
+            match self.state.compare_exchange_weak(
+                INCOMPLETE,
+                RUNNING,
+                Ordering::Acquire,
+                Ordering::Acquire,
+            ) {
+                Ok(_) => {
+                    let reset = ResetOnUnwind { once: self };
+                    f();
+                    std::mem::forget(reset);
+                    self.state.store(COMPLETE, Ordering::Release);
+                    return;
+                }
+                Err(COMPLETE) => return,
+                // someone's running it, only read until they're through
+                Err(RUNNING) => {
+                    while self.state.load(Ordering::Relaxed) == RUNNING {
+                        backoff(&mut spins, spin_limit);
+                    }
+                }
+                // `_weak` failing spuriously
+                Err(_) => {}
+            }
+        }
+    }
+
+    pub fn is_completed(&self) -> bool {
+        self.state.load(Ordering::Acquire) == COMPLETE
+    }
+}
+
+impl Default for SpinOnce {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+struct ResetOnUnwind<'a> {
+    once: &'a SpinOnce,
+}
+
+impl<'a> Drop for ResetOnUnwind<'a> {
+    fn drop(&mut self) {
+        self.once.state.store(INCOMPLETE, Ordering::Release);
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        panic,
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::SpinOnce;
+
+    #[test]
+    fn runs_once() {
+        let once = SpinOnce::new();
+        let calls = AtomicUsize::new(0);
+        let done = AtomicUsize::new(0);
+        thread::scope(|s| {
+            for _ in 0..8 {
+                s.spawn(|| {
+                    once.call_once(|| {
+                        thread::sleep(Duration::from_millis(10));
+                        calls.fetch_add(1, Ordering::Relaxed);
+                    });
+                    // whoever returns must see the initialization finished
+                    assert_eq!(calls.load(Ordering::Relaxed), 1);
+                    done.fetch_add(1, Ordering::Relaxed);
+                });
+            }
+        });
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(done.load(Ordering::Relaxed), 8);
+        assert!(once.is_completed());
+    }
+
+    #[test]
+    fn panic_allows_retry() {
+        let once = SpinOnce::new();
+        let r = panic::catch_unwind(|| once.call_once(|| panic!("init failed")));
+        assert!(r.is_err());
+        assert!(!once.is_completed());
+        let mut ran = false;
+        once.call_once(|| ran = true);
+        assert!(ran);
+        assert!(once.is_completed());
+    }
+}
@@ -0,0 +1,30 @@
+//! the primitives the locks are built from, `--cfg loom` swaps in loom's model-checked versions
+
+#[cfg(loom)]
+pub(crate) use loom::{sync::atomic, thread};
+#[cfg(not(loom))]
+pub(crate) use std::{sync::atomic, thread};
+
+/// `hint::spin_loop`, or nothing with the `no-spin-hint` feature for realtime targets
+/// where the pause instruction misbehaves. loom always gets it, that's where it switches threads
+#[inline]
+pub(crate) fn spin_hint() {
+    #[cfg(loom)]
+    loom::hint::spin_loop();
+    #[cfg(all(not(loom), not(feature = "no-spin-hint")))]
+    std::hint::spin_loop();
+}
+
+/// one round of waiting: `2, 4, .. 64` spin hints, then plain yields once `spin_limit`
+/// rounds are used up. loom takes every hint as a step, so it only gets one a round
+pub(crate) fn backoff(spins: &mut u32, spin_limit: u32) {
+    *spins += 1;
+    if *spins >= spin_limit {
+        thread::yield_now();
+        return;
+    }
+    let hints = if cfg!(loom) { 1 } else { 1 << (*spins).min(6) };
+    for _ in 0..hints {
+        spin_hint();
+    }
+}
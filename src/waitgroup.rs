@@ -0,0 +1,87 @@
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use crate::sync::backoff;
+
+/// lets a coordinator wait for a number of tasks to call `done`
+pub struct WaitGroup {
+    count: AtomicUsize,
+}
+
+impl WaitGroup {
+    pub const fn new() -> Self {
+        Self {
+            count: AtomicUsize::new(0),
+        }
+    }
+
+    pub fn add(&self, n: usize) {
+        self.count.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn done(&self) {
+        // release, so the task's work is visible to whoever returns from `wait`
+        let prev = self.count.fetch_sub(1, Ordering::Release);
+        assert!(prev != 0, "WaitGroup::done called more times than added");
+    }
+
+    /// spins while the count is above zero, backing off to yielding if it takes a while
+    pub fn wait(&self) {
+        let spin_limit = crate::lock::spin_limit();
+        let mut spins = 0;
+        while self.count.load(Ordering::Acquire) != 0 {
+            backoff(&mut spins, spin_limit);
+        }
+    }
+}
+
+impl Default for WaitGroup {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicUsize, Ordering},
+        thread,
+        time::Duration,
+    };
+
+    use super::WaitGroup;
+
+    #[test]
+    fn waits_for_all() {
+        const TASKS: usize = 6;
+        let wg = WaitGroup::new();
+        let finished = AtomicUsize::new(0);
+        wg.add(TASKS);
+        thread::scope(|s| {
+            for i in 0..TASKS {
+                let wg = &wg;
+                let finished = &finished;
+                s.spawn(move || {
+                    thread::sleep(Duration::from_millis(5 * i as u64));
+                    finished.fetch_add(1, Ordering::Relaxed);
+                    wg.done();
+                });
+            }
+            wg.wait();
+            assert_eq!(finished.load(Ordering::Relaxed), TASKS);
+        });
+    }
+
+    #[test]
+    fn zero_doesnt_wait() {
+        WaitGroup::new().wait();
+    }
+
+    #[test]
+    #[should_panic]
+    fn too_many_done() {
+        let wg = WaitGroup::new();
+        wg.add(1);
+        wg.done();
+        wg.done();
+    }
+}
@@ -0,0 +1,116 @@
+use std::sync::Arc;
+
+use crate::adeque::ADeque;
+
+/// the owning end of a work-stealing queue: the owner pushes and pops at the back,
+/// `Stealer`s take from the front so they get the oldest tasks
+pub struct Worker<T> {
+    deque: Arc<ADeque<T>>,
+}
+
+impl<T: Send + Sync> Worker<T> {
+    pub fn new() -> Self {
+        Self {
+            deque: Arc::new(ADeque::new()),
+        }
+    }
+
+    pub fn push(&self, task: T) {
+        self.deque.push_back(task);
+    }
+
+    /// the most recently pushed task, it's the one most likely still in cache
+    pub fn pop(&self) -> Option<T> {
+        self.deque.pop_back()
+    }
+
+    pub fn stealer(&self) -> Stealer<T> {
+        Stealer {
+            deque: self.deque.clone(),
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.deque.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.deque.is_empty()
+    }
+}
+
+impl<T: Send + Sync> Default for Worker<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// the other end of a `Worker`, as many as needed and on any thread
+pub struct Stealer<T> {
+    deque: Arc<ADeque<T>>,
+}
+
+impl<T: Send + Sync> Stealer<T> {
+    pub fn steal(&self) -> Option<T> {
+        self.deque.pop_front()
+    }
+}
+
+impl<T> Clone for Stealer<T> {
+    fn clone(&self) -> Self {
+        Self {
+            deque: self.deque.clone(),
+        }
+    }
+}
+
+#[cfg(all(test, not(loom)))]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+        thread,
+    };
+
+    use super::Worker;
+
+    #[test]
+    fn every_task_once() {
+        const TASKS: usize = 5000;
+        const STEALERS: usize = 3;
+        let runs: Vec<AtomicUsize> = (0..TASKS).map(|_| AtomicUsize::new(0)).collect();
+        let pushed_all = AtomicBool::new(false);
+        let worker = Worker::new();
+        let run = |task: usize| {
+            runs[task].fetch_add(1, Ordering::Relaxed);
+        };
+        thread::scope(|s| {
+            for _ in 0..STEALERS {
+                let stealer = worker.stealer();
+                let (pushed_all, run) = (&pushed_all, &run);
+                s.spawn(move || loop {
+                    match stealer.steal() {
+                        Some(task) => run(task),
+                        None if pushed_all.load(Ordering::Acquire) => break,
+                        None => thread::yield_now(),
+                    }
+                });
+            }
+            for task in 0..TASKS {
+                worker.push(task);
+                // the owner works through some of its own queue as well
+                if task % 3 == 0 {
+                    if let Some(task) = worker.pop() {
+                        run(task);
+                    }
+                }
+            }
+            pushed_all.store(true, Ordering::Release);
+            while let Some(task) = worker.pop() {
+                run(task);
+            }
+        });
+        assert_eq!(worker.len(), 0);
+        assert!(worker.is_empty());
+        assert!(runs.iter().all(|r| r.load(Ordering::Relaxed) == 1));
+    }
+}
@@ -0,0 +1,54 @@
+//! a failed allocation has to go through `handle_alloc_error` instead of writing through null.
+//! that aborts, so the failing part runs in a child process of this same test binary
+#![cfg(not(loom))]
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use rst_test::AVec;
+
+struct FailingAlloc;
+
+static FAIL: AtomicBool = AtomicBool::new(false);
+
+unsafe impl GlobalAlloc for FailingAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        // only the big one, the bookkeeping around it may still allocate
+        if FAIL.load(Ordering::Relaxed) && layout.size() >= BIG {
+            return std::ptr::null_mut();
+        }
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static GLOBAL: FailingAlloc = FailingAlloc;
+
+const BIG: usize = 1 << 20;
+const CHILD_ENV: &str = "AVEC_ALLOC_FAILURE_CHILD";
+
+#[test]
+fn reserve_aborts_on_failed_alloc() {
+    if std::env::var_os(CHILD_ENV).is_some() {
+        let avec = AVec::<u8>::new();
+        avec.push(1);
+        FAIL.store(true, Ordering::Relaxed);
+        avec.reserve(BIG);
+        FAIL.store(false, Ordering::Relaxed);
+        // only reachable if the failure went unnoticed
+        std::process::exit(0);
+    }
+    let out = Command::new(std::env::current_exe().unwrap())
+        .args(["--exact", "reserve_aborts_on_failed_alloc", "--nocapture"])
+        .env(CHILD_ENV, "1")
+        .output()
+        .unwrap();
+    assert!(!out.status.success());
+    let stderr = String::from_utf8_lossy(&out.stderr);
+    assert!(stderr.contains("memory allocation of"), "{stderr}");
+}
@@ -0,0 +1,43 @@
+//! which guards may cross threads: the positive cases are checked here,
+//! the negative ones are programs in `compile_fail/` that must not build
+#![cfg(not(loom))]
+
+use rst_test::{
+    aarray::AArray,
+    adeque::ADeque,
+    lock::{Lock, LockExclusiveGuard, LockSharedGuard},
+    spinmutex::{SpinMutex, SpinMutexGuard},
+    AVec, AVecRefElement,
+};
+
+fn assert_send<T: Send>() {}
+fn assert_sync<T: Sync>() {}
+
+#[test]
+fn send_sync() {
+    assert_send::<Lock<i32>>();
+    assert_sync::<Lock<i32>>();
+    assert_send::<LockSharedGuard<'static, i32>>();
+    assert_sync::<LockSharedGuard<'static, i32>>();
+    assert_send::<LockExclusiveGuard<'static, i32>>();
+    assert_sync::<LockExclusiveGuard<'static, i32>>();
+    assert_send::<SpinMutex<i32>>();
+    assert_sync::<SpinMutex<i32>>();
+    assert_send::<SpinMutexGuard<'static, i32>>();
+    assert_sync::<SpinMutexGuard<'static, i32>>();
+    assert_send::<AVec<i32>>();
+    assert_sync::<AVec<i32>>();
+    assert_send::<AVec<String>>();
+    assert_sync::<AVec<String>>();
+    assert_send::<AVecRefElement<'static, String>>();
+    assert_sync::<AVecRefElement<'static, String>>();
+    assert_send::<AArray<String, 4>>();
+    assert_sync::<AArray<String, 4>>();
+    assert_send::<ADeque<String>>();
+    assert_sync::<ADeque<String>>();
+}
+
+#[test]
+fn compile_fail() {
+    trybuild::TestCases::new().compile_fail("tests/compile_fail/*.rs");
+}
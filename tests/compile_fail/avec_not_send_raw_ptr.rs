@@ -0,0 +1,10 @@
+use rst_test::AVec;
+
+struct RawPtr(*mut i32);
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+    // the elements would end up dropped on whatever thread drops the AVec
+    assert_send::<AVec<RawPtr>>();
+}
@@ -0,0 +1,10 @@
+use std::sync::MutexGuard;
+
+use rst_test::AVec;
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+    // a push from another thread would move the `MutexGuard` over
+    assert_sync::<AVec<MutexGuard<'static, i32>>>();
+}
@@ -0,0 +1,9 @@
+use std::rc::Rc;
+
+use rst_test::AVecRefElement;
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+    assert_send::<AVecRefElement<'static, Rc<i32>>>();
+}
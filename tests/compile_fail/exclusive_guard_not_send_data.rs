@@ -0,0 +1,10 @@
+use std::sync::MutexGuard;
+
+use rst_test::lock::LockExclusiveGuard;
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+    // could `mem::replace` the `MutexGuard` out on another thread
+    assert_send::<LockExclusiveGuard<'static, MutexGuard<'static, i32>>>();
+}
@@ -0,0 +1,9 @@
+use rst_test::lock::Lock;
+
+struct RawPtr(*mut i32);
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+    assert_send::<Lock<RawPtr>>();
+}
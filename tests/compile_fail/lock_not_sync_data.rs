@@ -0,0 +1,9 @@
+use std::rc::Rc;
+
+use rst_test::lock::Lock;
+
+fn assert_sync<T: Sync>() {}
+
+fn main() {
+    assert_sync::<Lock<Rc<i32>>>();
+}
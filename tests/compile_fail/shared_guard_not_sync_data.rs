@@ -0,0 +1,10 @@
+use std::cell::Cell;
+
+use rst_test::lock::LockSharedGuard;
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+    // `Cell` isn't `Sync`, so a reader on another thread would race the owner
+    assert_send::<LockSharedGuard<'static, Cell<i32>>>();
+}
@@ -0,0 +1,9 @@
+use std::cell::Cell;
+
+use rst_test::spinmutex::SpinMutexGuard;
+
+fn assert_send<T: Send>() {}
+
+fn main() {
+    assert_send::<SpinMutexGuard<'static, Cell<i32>>>();
+}
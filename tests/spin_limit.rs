@@ -0,0 +1,25 @@
+//! `set_spin_limit` changes the limit for the whole process, so it's tried here in its own
+//! test binary where it can't change anything under the crate's other tests
+#![cfg(not(loom))]
+
+use std::thread;
+
+use rst_test::lock::{set_spin_limit, Lock};
+
+#[test]
+fn low_spin_limit() {
+    set_spin_limit(1);
+    let lock = Lock::new(0);
+    thread::scope(|s| {
+        for _ in 0..4 {
+            s.spawn(|| {
+                for _ in 0..200 {
+                    *lock.lock_exclusive() += 1;
+                    let _ = *lock.lock_shared();
+                }
+            });
+        }
+    });
+    set_spin_limit(64);
+    assert_eq!(*lock.lock_shared(), 800);
+}